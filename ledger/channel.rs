@@ -0,0 +1,1325 @@
+//
+// Copyright 2022 The Project Oak Authors // oak/oak_restricted_kernel_sdk/src/channel.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Provides functionality to communicate with host application over the
+//! communication channel.
+//!
+//! For debugging the channel, [`start_blocking_server`] can be built in one of
+//! the diagnostic [`ServerMode`]s by enabling the matching cargo feature.
+//!
+//! When the host closes the channel, reads fail with [`ChannelError`] and
+//! [`start_blocking_server`] waits for the host to reconnect. Since the host
+//! can't know where the guest stopped reading, the guest then skips ahead to
+//! the next frame that starts a message.
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt::{self, Write as _},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+};
+
+use anyhow::{anyhow, Context};
+use oak_channel::Channel;
+pub use oak_channel::{Read, Write};
+use oak_core::{samplestore::SampleStore, timer::Timer};
+use oak_restricted_kernel_interface::OAK_CHANNEL_FD;
+use prost::Message;
+
+/// Error of a channel whose other end went away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelError {
+    /// The host closed the channel before any of the data was transferred.
+    Closed,
+    /// The host closed the channel after only `transferred` of `expected`
+    /// bytes were transferred.
+    Truncated { transferred: usize, expected: usize },
+}
+
+impl ChannelError {
+    /// Returns the [`ChannelError`] behind the error, if any.
+    pub fn find(err: &anyhow::Error) -> Option<ChannelError> {
+        err.downcast_ref::<ChannelError>().copied()
+    }
+
+    fn new(transferred: usize, expected: usize) -> Self {
+        if transferred == 0 {
+            ChannelError::Closed
+        } else {
+            ChannelError::Truncated { transferred, expected }
+        }
+    }
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::Closed => f.write_str("channel closed"),
+            ChannelError::Truncated { transferred, expected } => {
+                write!(f, "channel closed after {} of {} bytes", transferred, expected)
+            }
+        }
+    }
+}
+
+impl core::error::Error for ChannelError {}
+
+/// The syscalls [`FileDescriptorChannel`] makes, so that it can also run on a
+/// host, e.g. in tests.
+pub trait Syscalls {
+    /// Reads up to `data.len()` bytes, returning how many were read.
+    fn read(&self, fd: i32, data: &mut [u8]) -> anyhow::Result<usize>;
+
+    /// Writes up to `data.len()` bytes, returning how many were written.
+    fn write(&self, fd: i32, data: &[u8]) -> anyhow::Result<usize>;
+
+    fn fsync(&self, fd: i32) -> anyhow::Result<()>;
+}
+
+/// The restricted kernel's syscalls.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelSyscalls;
+
+impl Syscalls for KernelSyscalls {
+    fn read(&self, fd: i32, data: &mut [u8]) -> anyhow::Result<usize> {
+        oak_restricted_kernel_interface::syscall::read(fd, data)
+            .map_err(|err| anyhow!("read failure: {}", err))
+    }
+
+    fn write(&self, fd: i32, data: &[u8]) -> anyhow::Result<usize> {
+        oak_restricted_kernel_interface::syscall::write(fd, data)
+            .map_err(|err| anyhow!("write failure: {}", err))
+    }
+
+    fn fsync(&self, fd: i32) -> anyhow::Result<()> {
+        oak_restricted_kernel_interface::syscall::fsync(fd)
+            .map_err(|err| anyhow!("sync failure: {}", err))
+    }
+}
+
+/// Channel that communicates over a file descriptor.
+///
+/// A read or write that makes no progress means the host closed the channel
+/// and fails with [`ChannelError`].
+pub struct FileDescriptorChannel<S: Syscalls = KernelSyscalls> {
+    fd: i32,
+    syscalls: S,
+}
+
+impl FileDescriptorChannel {
+    pub fn new(fd: i32) -> Self {
+        Self::with_syscalls(fd, KernelSyscalls)
+    }
+}
+
+impl<S: Syscalls> FileDescriptorChannel<S> {
+    /// Constructs a channel that makes its syscalls through `syscalls`.
+    pub fn with_syscalls(fd: i32, syscalls: S) -> Self {
+        Self { fd, syscalls }
+    }
+
+    /// Reads whatever is available, up to the size of `data`, with a single
+    /// syscall. Returns 0 if the host closed the channel.
+    fn read_some(&mut self, data: &mut [u8]) -> anyhow::Result<usize> {
+        self.syscalls.read(self.fd, data)
+    }
+}
+
+impl Default for FileDescriptorChannel {
+    /// Constructs a new FileDescriptorChannel that assumes we'll use the
+    /// well-known Oak file descriptor number.
+    fn default() -> Self {
+        Self::new(OAK_CHANNEL_FD)
+    }
+}
+
+impl<S: Syscalls> Read for FileDescriptorChannel<S> {
+    fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        let len = data.len();
+        let mut remaining = data.len();
+
+        while remaining > 0 {
+            let read = self.read_some(&mut data[len - remaining..])?;
+            if read == 0 {
+                return Err(anyhow!(ChannelError::new(len - remaining, len)));
+            }
+            remaining -= read;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Syscalls> Write for FileDescriptorChannel<S> {
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let len = data.len();
+        let mut remaining = data.len();
+
+        while remaining > 0 {
+            let written = self.syscalls.write(self.fd, &data[len - remaining..])?;
+            if written == 0 {
+                return Err(anyhow!(ChannelError::new(len - remaining, len)));
+            }
+            remaining -= written;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.syscalls.fsync(self.fd)
+    }
+}
+
+/// Default size of the [`BufferedChannel`] read buffer.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Default size of the [`BufferedChannel`] write buffer. Fits a dozen or so
+/// full `oak_channel` frames.
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// [`FileDescriptorChannel`] with read and write buffers.
+///
+/// Reads fetch as much as is available into the read buffer, so a frame
+/// header and its body usually take a single syscall. Writes are collected in
+/// the write buffer and go out together, so all the frames of a message are
+/// written with as few syscalls as the buffer size allows, when the channel is
+/// flushed or the buffer is full. Reads and writes at least as large as the
+/// buffer bypass it.
+pub struct BufferedChannel<S: Syscalls = KernelSyscalls> {
+    inner: FileDescriptorChannel<S>,
+    read_buffer: Box<[u8]>,
+    read_start: usize,
+    read_end: usize,
+    write_buffer: Vec<u8>,
+    sync_on_flush: bool,
+}
+
+impl<S: Syscalls> BufferedChannel<S> {
+    /// Wraps the channel with buffers of the default sizes.
+    pub fn new(inner: FileDescriptorChannel<S>) -> Self {
+        Self::with_capacity(DEFAULT_READ_BUFFER_SIZE, DEFAULT_WRITE_BUFFER_SIZE, inner)
+    }
+
+    /// Wraps the channel with buffers of the given sizes.
+    pub fn with_capacity(
+        read_capacity: usize,
+        write_capacity: usize,
+        inner: FileDescriptorChannel<S>,
+    ) -> Self {
+        Self {
+            inner,
+            read_buffer: alloc::vec![0; read_capacity].into_boxed_slice(),
+            read_start: 0,
+            read_end: 0,
+            write_buffer: Vec::with_capacity(write_capacity),
+            sync_on_flush: false,
+        }
+    }
+
+    /// Sets whether flushing also syncs the file descriptor. By default a flush
+    /// only writes out the write buffer.
+    pub fn sync_on_flush(mut self, sync_on_flush: bool) -> Self {
+        self.sync_on_flush = sync_on_flush;
+        self
+    }
+
+    /// Writes out the write buffer. Its contents are dropped even if that
+    /// fails, so that a reconnected host doesn't get the rest of a stale
+    /// message.
+    fn write_buffered(&mut self) -> anyhow::Result<()> {
+        if self.write_buffer.is_empty() {
+            return Ok(());
+        }
+        let result = self.inner.write_all(&self.write_buffer);
+        self.write_buffer.clear();
+        result
+    }
+}
+
+impl Default for BufferedChannel {
+    /// Buffers the channel on the well-known Oak file descriptor number.
+    fn default() -> Self {
+        Self::new(FileDescriptorChannel::default())
+    }
+}
+
+impl<S: Syscalls> Read for BufferedChannel<S> {
+    fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        let len = data.len();
+        let mut filled = 0;
+
+        while filled < len {
+            if self.read_start == self.read_end {
+                let read = if len - filled >= self.read_buffer.len() {
+                    let read = self.inner.read_some(&mut data[filled..])?;
+                    filled += read;
+                    read
+                } else {
+                    let read = self.inner.read_some(&mut self.read_buffer)?;
+                    self.read_start = 0;
+                    self.read_end = read;
+                    read
+                };
+                if read == 0 {
+                    return Err(anyhow!(ChannelError::new(filled, len)));
+                }
+                continue;
+            }
+            let count = (self.read_end - self.read_start).min(len - filled);
+            data[filled..filled + count]
+                .copy_from_slice(&self.read_buffer[self.read_start..self.read_start + count]);
+            self.read_start += count;
+            filled += count;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Syscalls> Write for BufferedChannel<S> {
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if self.write_buffer.len() + data.len() > self.write_buffer.capacity() {
+            self.write_buffered()?;
+        }
+        if data.len() >= self.write_buffer.capacity() {
+            return self.inner.write_all(data);
+        }
+        self.write_buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_buffered()?;
+        if self.sync_on_flush {
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// What [`start_blocking_server`] does with the channel.
+///
+/// Every mode other than [`ServerMode::Normal`] is a diagnostic for testing the
+/// host side of the channel and is selected at build time with the cargo
+/// feature named after it; at most one of them can be enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerMode {
+    /// Serves requests from the host. The default.
+    Normal,
+    /// Invokes the server once with [`CONSTANT_REQUEST_BODY`] instead of a
+    /// request read from the host, drops the response and halts. Feature
+    /// `channel_constant_request`.
+    ConstantRequest,
+    /// Sends the host an unsolicited response with [`PROBE_BODY`] and halts.
+    /// Feature `channel_probe_send`.
+    ProbeSend,
+    /// Reads requests from the host without responding. Unlike [`Idle`], this
+    /// drains the channel, so the host's writes don't back up. Feature
+    /// `channel_receive_only`.
+    ///
+    /// [`Idle`]: ServerMode::Idle
+    ReceiveOnly,
+    /// Never touches the channel and halts, as the old `no_ledger_send`
+    /// variant did. Feature `channel_idle`.
+    Idle,
+    /// Responds to every request with its own body, without invoking the
+    /// server. Feature `channel_echo`.
+    Echo,
+}
+
+const ENABLED_DIAGNOSTIC_MODES: usize = cfg!(feature = "channel_constant_request") as usize
+    + cfg!(feature = "channel_probe_send") as usize
+    + cfg!(feature = "channel_receive_only") as usize
+    + cfg!(feature = "channel_echo") as usize
+    + cfg!(feature = "channel_idle") as usize;
+const _: () = assert!(
+    ENABLED_DIAGNOSTIC_MODES < 2,
+    "at most one of the channel_* diagnostic features can be enabled"
+);
+
+impl ServerMode {
+    /// The mode selected by the enabled cargo feature.
+    pub const SELECTED: ServerMode = if cfg!(feature = "channel_constant_request") {
+        ServerMode::ConstantRequest
+    } else if cfg!(feature = "channel_probe_send") {
+        ServerMode::ProbeSend
+    } else if cfg!(feature = "channel_receive_only") {
+        ServerMode::ReceiveOnly
+    } else if cfg!(feature = "channel_echo") {
+        ServerMode::Echo
+    } else if cfg!(feature = "channel_idle") {
+        ServerMode::Idle
+    } else {
+        ServerMode::Normal
+    };
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ServerMode::Normal => "normal",
+            ServerMode::ConstantRequest => "constant-request",
+            ServerMode::ProbeSend => "probe-send",
+            ServerMode::ReceiveOnly => "receive-only",
+            ServerMode::Echo => "echo",
+            ServerMode::Idle => "idle",
+        }
+    }
+}
+
+/// Invocation ID of the request used in [`ServerMode::ConstantRequest`].
+pub const CONSTANT_REQUEST_INVOCATION_ID: u32 = 42;
+
+/// Body of the request used in [`ServerMode::ConstantRequest`].
+pub const CONSTANT_REQUEST_BODY: &[u8] = b"constant request body";
+
+/// Invocation ID of the response sent in [`ServerMode::ProbeSend`].
+pub const PROBE_INVOCATION_ID: u32 = 10;
+
+/// Body of the response sent in [`ServerMode::ProbeSend`].
+pub const PROBE_BODY: &[u8] = b"qwertyuiop\n";
+
+/// Starts a blocking server that listens for requests on the provided channel
+/// and responds to them using the provided [`micro_rpc::Transport`].
+///
+/// Behaves according to [`ServerMode::SELECTED`]. Pass a [`BufferedChannel`] to
+/// read and write the channel through buffers.
+///
+/// A request that can't be decoded is dropped: if its invocation ID could be
+/// read it's answered with an `INVALID_ARGUMENT` error, and the server skips
+/// ahead to the next frame that starts a message. If the host closes the
+/// channel the server waits for it to reconnect and then skips ahead the same
+/// way. Only other errors of the channel itself end the server.
+pub fn start_blocking_server<T: micro_rpc::Transport<Error = !>>(
+    channel: Box<dyn Channel>,
+    mut server: T,
+    stats: &mut dyn SampleStore,
+) -> anyhow::Result<!> {
+    let read_state = Arc::new(ReadState::default());
+    let channel = Box::new(RecoveringChannel::new(channel, read_state.clone()));
+    let channel_handle = &mut oak_channel::server::ServerChannelHandle::new(channel);
+    let samples = &mut SampleBatch::new();
+    let mode = ServerMode::SELECTED;
+    if mode != ServerMode::Normal {
+        log::warn!("channel diagnostic mode: {}", mode.as_str());
+    }
+
+    match mode {
+        ServerMode::ConstantRequest => {
+            let timer = Timer::new_rdtsc();
+            log::debug!(
+                "using constant request message with invocation id {} ({} bytes)",
+                CONSTANT_REQUEST_INVOCATION_ID,
+                CONSTANT_REQUEST_BODY.len()
+            );
+            let response = server.invoke(CONSTANT_REQUEST_BODY).into_ok();
+            log::debug!("dropping response message ({} bytes)", response.len());
+            record_sample(stats, samples, &timer);
+            samples.report();
+            halt()
+        }
+        ServerMode::ProbeSend => {
+            let probe_message = oak_channel::message::ResponseMessage {
+                invocation_id: PROBE_INVOCATION_ID,
+                body: PROBE_BODY.to_vec(),
+            };
+            log::debug!("sending probe response message {:?}", probe_message);
+            channel_handle.write_response(probe_message)?;
+            halt()
+        }
+        ServerMode::Idle => halt(),
+        ServerMode::Normal | ServerMode::ReceiveOnly | ServerMode::Echo => {}
+    }
+
+    loop {
+        if read_state.reconnect.load(Ordering::Relaxed) {
+            samples.report();
+        }
+        log::debug!("waiting for a request message");
+        read_state.start_request();
+        let (request_message, timer) = match channel_handle.read_request() {
+            Ok(request) => request,
+            Err(err) if read_state.failed.load(Ordering::Relaxed) => {
+                reconnect_after(&read_state, err).context("couldn't receive message")?;
+                continue;
+            }
+            Err(err) => {
+                log::warn!("dropping malformed request message: {:?}", err);
+                read_state.resync.store(true, Ordering::Relaxed);
+                if let Some(invocation_id) = read_state.invocation_id() {
+                    log::debug!("sending error response with invocation id {}", invocation_id);
+                    let response_message = oak_channel::message::ResponseMessage {
+                        invocation_id,
+                        body: error_response(
+                            micro_rpc::StatusCode::InvalidArgument,
+                            "malformed request message",
+                        ),
+                    };
+                    if let Err(err) = channel_handle.write_response(response_message) {
+                        reconnect_after(&read_state, err)?;
+                    }
+                }
+                continue;
+            }
+        };
+        let request_message_invocation_id = request_message.invocation_id;
+        log::debug!(
+            "received request message with invocation id {} ({} bytes)",
+            request_message_invocation_id,
+            request_message.body.len()
+        );
+        let response = match mode {
+            ServerMode::ReceiveOnly => continue,
+            ServerMode::Echo => request_message.body,
+            _ => server.invoke(request_message.body.as_ref()).into_ok(),
+        };
+        log::debug!(
+            "sending response message with invocation id {} ({} bytes)",
+            request_message_invocation_id,
+            response.len()
+        );
+        let response_message = oak_channel::message::ResponseMessage {
+            invocation_id: request_message_invocation_id,
+            body: response,
+        };
+        match channel_handle.write_response(response_message) {
+            Ok(()) => record_sample(stats, samples, &timer),
+            Err(err) => reconnect_after(&read_state, err)?,
+        }
+    }
+}
+
+/// Makes the server wait for the host to reconnect if the error means that the
+/// host went away, or returns the error otherwise.
+fn reconnect_after(read_state: &ReadState, err: anyhow::Error) -> anyhow::Result<()> {
+    let Some(channel_error) = ChannelError::find(&err) else {
+        return Err(err);
+    };
+    log::warn!("host went away ({}); waiting for it to reconnect", channel_error);
+    read_state.failed.store(false, Ordering::Relaxed);
+    read_state.reconnect.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Records how long handling a request took.
+fn record_sample(stats: &mut dyn SampleStore, samples: &mut SampleBatch, timer: &Timer) {
+    let elapsed = timer.elapsed();
+    stats.record(elapsed);
+    samples.push(elapsed);
+    if samples.is_due() {
+        samples.report();
+    }
+}
+
+/// Number of request durations reported to the launcher per console line.
+const SAMPLE_BATCH_SIZE: usize = 64;
+
+/// Ticks after which [`start_blocking_server`] reports a partial batch of
+/// samples with the next request, around half a second.
+const SAMPLE_REPORT_INTERVAL_TICKS: u64 = 1 << 30;
+
+/// Request durations waiting to be reported to the launcher, which exports
+/// console lines of the form `oak_sample <name> <value>...` as metrics.
+///
+/// The samples are logged at info level so that they're there without debug
+/// logging, and in batches so that requests don't each pay for a console
+/// write. A batch is reported when it's full, after a request once
+/// [`SAMPLE_REPORT_INTERVAL_TICKS`] have passed since the last report, when the
+/// host went away, and when the server ends. A guest that serves few requests
+/// thus reports each of them. The server can't tell whether it's about to block
+/// waiting for a request, so the samples of a burst that ends sooner stay
+/// pending until the next request arrives, and are lost if the guest is killed
+/// before that.
+struct SampleBatch {
+    samples: Vec<u64>,
+    last_report: Timer,
+}
+
+impl SampleBatch {
+    fn new() -> Self {
+        Self { samples: Vec::with_capacity(SAMPLE_BATCH_SIZE), last_report: Timer::new_rdtsc() }
+    }
+
+    fn push(&mut self, sample: u64) {
+        self.samples.push(sample);
+        if self.samples.len() >= SAMPLE_BATCH_SIZE {
+            self.report();
+        }
+    }
+
+    /// Whether pending samples have waited long enough to be reported without
+    /// filling the batch.
+    fn is_due(&self) -> bool {
+        !self.samples.is_empty() && self.last_report.elapsed() >= SAMPLE_REPORT_INTERVAL_TICKS
+    }
+
+    /// Logs the pending samples, if there are any.
+    fn report(&mut self) {
+        self.last_report = Timer::new_rdtsc();
+        if self.samples.is_empty() {
+            return;
+        }
+        let mut line = String::from("oak_sample request_ticks");
+        for sample in self.samples.drain(..) {
+            let _ = write!(line, " {}", sample);
+        }
+        log::info!("{}", line);
+    }
+}
+
+impl Drop for SampleBatch {
+    /// Reports what's left when the server ends.
+    fn drop(&mut self) {
+        self.report();
+    }
+}
+
+/// Parks the server once a one-shot diagnostic is done, keeping the guest
+/// running so the host side can be inspected.
+fn halt() -> ! {
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Frame layout of `oak_channel`, which the server needs to find its way back to
+/// a frame boundary: a little-endian `u32` length of the whole frame,
+/// little-endian `u16` flags, then the body. The body of the first frame of a
+/// message starts with the little-endian `u32` invocation ID.
+///
+/// `oak_channel` doesn't export its layout, so this is the one place outside of
+/// it that knows it; the tests check it against frames `oak_channel` writes.
+pub mod frame {
+    pub const LENGTH_SIZE: usize = 4;
+    pub const HEADER_SIZE: usize = LENGTH_SIZE + 2;
+    /// Largest frame, header included.
+    pub const MAX_SIZE: usize = 4000;
+    pub const MAX_BODY_SIZE: usize = MAX_SIZE - HEADER_SIZE;
+    /// Set on the first frame of a message.
+    pub const FLAG_START: u16 = 1;
+    /// Set on the last frame of a message.
+    pub const FLAG_END: u16 = 2;
+    pub const INVOCATION_ID_SIZE: usize = 4;
+}
+
+/// Spin loop iterations between the first two polls of a closed channel. Later
+/// polls back off up to [`RECONNECT_MAX_SPINS`].
+const RECONNECT_MIN_SPINS: u32 = 1 << 10;
+const RECONNECT_MAX_SPINS: u32 = 1 << 24;
+
+/// What [`RecoveringChannel`] saw of the request [`start_blocking_server`] is
+/// currently reading.
+#[derive(Default)]
+struct ReadState {
+    /// Bytes of the current request read so far.
+    offset: AtomicUsize,
+    /// The invocation ID bytes of the current request read so far.
+    invocation_id: AtomicU32,
+    /// Set once the underlying channel failed, which can't be recovered from.
+    failed: AtomicBool,
+    /// Set when the next read has to skip ahead to a frame boundary first.
+    resync: AtomicBool,
+    /// Set when the next read has to wait for the host to reconnect first.
+    reconnect: AtomicBool,
+}
+
+impl ReadState {
+    fn start_request(&self) {
+        self.offset.store(0, Ordering::Relaxed);
+        self.invocation_id.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the invocation ID of the current request, if it was read.
+    fn invocation_id(&self) -> Option<u32> {
+        (self.offset.load(Ordering::Relaxed) >= frame::HEADER_SIZE + frame::INVOCATION_ID_SIZE)
+            .then(|| self.invocation_id.load(Ordering::Relaxed))
+    }
+
+    /// Notes that the given bytes of the current request were read.
+    fn observe(&self, data: &[u8]) {
+        let start = self.offset.fetch_add(data.len(), Ordering::Relaxed);
+        let end = start + data.len();
+        let id_start = frame::HEADER_SIZE;
+        let id_end = frame::HEADER_SIZE + frame::INVOCATION_ID_SIZE;
+        for position in start.max(id_start)..end.min(id_end) {
+            let byte = u32::from(data[position - start]);
+            self.invocation_id.fetch_or(byte << (8 * (position - id_start)), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Channel that [`start_blocking_server`] reads requests through, keeping
+/// track of them in a shared [`ReadState`].
+struct RecoveringChannel {
+    inner: Box<dyn Channel>,
+    state: Arc<ReadState>,
+    /// Bytes read ahead while resynchronising, returned before any new ones.
+    pending: Vec<u8>,
+}
+
+impl RecoveringChannel {
+    fn new(inner: Box<dyn Channel>, state: Arc<ReadState>) -> Self {
+        Self { inner, state, pending: Vec::new() }
+    }
+
+    /// Skips input until what follows looks like the header of a frame that
+    /// starts a message.
+    fn resync(&mut self) -> anyhow::Result<()> {
+        let mut window = core::mem::take(&mut self.pending);
+        let mut skipped = 0;
+        loop {
+            while window.len() < frame::HEADER_SIZE {
+                let mut byte = [0];
+                self.inner.read_exact(&mut byte)?;
+                window.push(byte[0]);
+            }
+            if is_message_start(&window[..frame::HEADER_SIZE]) {
+                break;
+            }
+            window.remove(0);
+            skipped += 1;
+        }
+        if skipped > 0 {
+            log::warn!("skipped {} bytes to find the next request", skipped);
+        }
+        self.pending = window;
+        Ok(())
+    }
+
+    /// Waits for the host to reopen the channel, then skips ahead to the next
+    /// request.
+    fn reconnect(&mut self) -> anyhow::Result<()> {
+        let mut header = [0; frame::HEADER_SIZE];
+        let mut spins = RECONNECT_MIN_SPINS;
+        loop {
+            match self.inner.read_exact(&mut header) {
+                Ok(()) => break,
+                Err(err) if ChannelError::find(&err).is_some() => {
+                    for _ in 0..spins {
+                        core::hint::spin_loop();
+                    }
+                    spins = (spins * 2).min(RECONNECT_MAX_SPINS);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        log::info!("host reconnected");
+        self.pending.clear();
+        self.pending.extend_from_slice(&header);
+        self.resync()
+    }
+
+    fn read_from_channel(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        if self.state.reconnect.swap(false, Ordering::Relaxed) {
+            self.state.resync.store(false, Ordering::Relaxed);
+            self.reconnect()?;
+        } else if self.state.resync.swap(false, Ordering::Relaxed) {
+            self.resync()?;
+        }
+        let from_pending = data.len().min(self.pending.len());
+        data[..from_pending].copy_from_slice(&self.pending[..from_pending]);
+        self.pending.drain(..from_pending);
+        if from_pending < data.len() {
+            self.inner.read_exact(&mut data[from_pending..])?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for RecoveringChannel {
+    fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        if let Err(err) = self.read_from_channel(data) {
+            self.state.failed.store(true, Ordering::Relaxed);
+            return Err(err);
+        }
+        self.state.observe(data);
+        Ok(())
+    }
+}
+
+impl Write for RecoveringChannel {
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.inner.write_all(data)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Checks whether the bytes look like the header of a frame that starts a
+/// message.
+fn is_message_start(header: &[u8]) -> bool {
+    let (length, flags) = header.split_at(frame::LENGTH_SIZE);
+    let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+    let flags = u16::from_le_bytes(flags.try_into().unwrap());
+    (frame::HEADER_SIZE..=frame::MAX_SIZE).contains(&length)
+        && flags & frame::FLAG_START != 0
+        && flags & !(frame::FLAG_START | frame::FLAG_END) == 0
+}
+
+/// Encodes a micro RPC `ResponseWrapper` carrying an error `Status`, which the
+/// client decodes in place of a response body.
+fn error_response(code: micro_rpc::StatusCode, message: &str) -> Vec<u8> {
+    micro_rpc::ResponseWrapper::from(Err(micro_rpc::Status::new_with_message(code, message)))
+        .encode_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{
+        format, io,
+        mem::ManuallyDrop,
+        os::{
+            fd::{AsRawFd, FromRawFd},
+            unix::net::UnixStream,
+        },
+        string::String,
+        sync::{mpsc, Mutex, Once},
+        thread::{self, JoinHandle},
+    };
+
+    use oak_channel::{client::ClientChannelHandle, message::RequestMessage};
+    use oak_core::samplestore::StaticSampleStore;
+
+    use super::*;
+
+    /// [`Syscalls`] on a Linux host, counting the calls.
+    #[derive(Clone, Default)]
+    struct HostSyscalls {
+        reads: Arc<AtomicUsize>,
+        writes: Arc<AtomicUsize>,
+        /// Number of reads that report the channel as closed without reading.
+        closed_reads: Arc<AtomicUsize>,
+        /// Whether the end of the socket is an error rather than a closed
+        /// channel, which stops the server instead of making it wait for a
+        /// reconnect.
+        end_is_error: bool,
+    }
+
+    impl HostSyscalls {
+        /// Borrows the socket behind the file descriptor, without closing it.
+        fn socket(fd: i32) -> ManuallyDrop<UnixStream> {
+            // SAFETY: the tests keep the socket open for as long as the channel uses it.
+            ManuallyDrop::new(unsafe { UnixStream::from_raw_fd(fd) })
+        }
+    }
+
+    impl Syscalls for HostSyscalls {
+        fn read(&self, fd: i32, data: &mut [u8]) -> anyhow::Result<usize> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            let closed =
+                self.closed_reads
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+            if closed.is_ok() {
+                return Ok(0);
+            }
+            let read = io::Read::read(&mut &*Self::socket(fd), data)?;
+            if read == 0 && self.end_is_error {
+                anyhow::bail!("end of socket");
+            }
+            Ok(read)
+        }
+
+        fn write(&self, fd: i32, data: &[u8]) -> anyhow::Result<usize> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Ok(io::Write::write(&mut &*Self::socket(fd), data)?)
+        }
+
+        fn fsync(&self, _fd: i32) -> anyhow::Result<()> {
+            // Sockets can't be synced.
+            Ok(())
+        }
+    }
+
+    /// Responds with the request reversed, and passes the request on to the
+    /// sender if there is one.
+    #[derive(Default)]
+    struct Reverse(Option<mpsc::Sender<Vec<u8>>>);
+
+    impl micro_rpc::Transport for Reverse {
+        type Error = !;
+
+        fn invoke(&mut self, request: &[u8]) -> Result<Vec<u8>, !> {
+            if let Some(requests) = &self.0 {
+                requests.send(request.to_vec()).unwrap();
+            }
+            Ok(request.iter().rev().copied().collect())
+        }
+    }
+
+    fn channel(socket: &UnixStream, syscalls: HostSyscalls) -> FileDescriptorChannel<HostSyscalls> {
+        FileDescriptorChannel::with_syscalls(socket.as_raw_fd(), syscalls)
+    }
+
+    /// Runs [`start_blocking_server`] on one end of a socketpair, returning
+    /// the other end.
+    fn spawn_server(
+        syscalls: HostSyscalls,
+        server: Reverse,
+    ) -> (UnixStream, JoinHandle<anyhow::Result<!>>) {
+        spawn_server_with(syscalls, server, |channel| Box::new(channel))
+    }
+
+    /// Like [`spawn_server`], but lets `wrap` put something in front of the
+    /// server's channel.
+    fn spawn_server_with(
+        syscalls: HostSyscalls,
+        server: Reverse,
+        wrap: fn(FileDescriptorChannel<HostSyscalls>) -> Box<dyn Channel>,
+    ) -> (UnixStream, JoinHandle<anyhow::Result<!>>) {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let channel = wrap(channel(&guest, syscalls));
+            let mut stats = StaticSampleStore::<16>::new().unwrap();
+            start_blocking_server(channel, server, &mut stats)
+        });
+        (host, server)
+    }
+
+    fn client(host: &UnixStream) -> ClientChannelHandle {
+        ClientChannelHandle::new(Box::new(channel(host, HostSyscalls::default())))
+    }
+
+    // Not every diagnostic mode answers requests.
+    #[cfg_attr(
+        any(
+            feature = "channel_constant_request",
+            feature = "channel_probe_send",
+            feature = "channel_receive_only",
+            feature = "channel_idle"
+        ),
+        allow(dead_code)
+    )]
+    fn request(client: &mut ClientChannelHandle, invocation_id: u32, body: &[u8]) -> Vec<u8> {
+        client.write_request(RequestMessage { invocation_id, body: body.to_vec() }).unwrap();
+        let response = client.read_response().unwrap();
+        assert_eq!(response.invocation_id, invocation_id);
+        response.body
+    }
+
+    #[cfg_attr(
+        any(
+            feature = "channel_constant_request",
+            feature = "channel_probe_send",
+            feature = "channel_idle"
+        ),
+        allow(dead_code)
+    )]
+    fn stop(host: UnixStream, server: JoinHandle<anyhow::Result<!>>) {
+        drop(host);
+        let err = server.join().unwrap().unwrap_err();
+        assert!(ChannelError::find(&err).is_none(), "unexpected error: {:?}", err);
+    }
+
+    #[test]
+    fn read_exact_reads_everything() {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let mut channel = channel(&guest, HostSyscalls::default());
+        io::Write::write_all(&mut &host, b"hello world").unwrap();
+
+        let mut data = [0; 11];
+        channel.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"hello world");
+    }
+
+    #[test]
+    fn read_exact_reports_closed_channel() {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let mut channel = channel(&guest, HostSyscalls::default());
+        drop(host);
+
+        let err = channel.read_exact(&mut [0; 4]).unwrap_err();
+        assert_eq!(ChannelError::find(&err), Some(ChannelError::Closed));
+    }
+
+    #[test]
+    fn read_exact_reports_truncated_read() {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let mut channel = channel(&guest, HostSyscalls::default());
+        io::Write::write_all(&mut &host, b"abc").unwrap();
+        drop(host);
+
+        let err = channel.read_exact(&mut [0; 5]).unwrap_err();
+        assert_eq!(
+            ChannelError::find(&err),
+            Some(ChannelError::Truncated { transferred: 3, expected: 5 })
+        );
+    }
+
+    #[test]
+    fn buffered_channel_coalesces_writes() {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let syscalls = HostSyscalls::default();
+        let mut channel = BufferedChannel::new(channel(&guest, syscalls.clone()));
+
+        channel.write_all(b"header").unwrap();
+        channel.write_all(b"body").unwrap();
+        assert_eq!(syscalls.writes.load(Ordering::Relaxed), 0);
+        channel.flush().unwrap();
+        assert_eq!(syscalls.writes.load(Ordering::Relaxed), 1);
+
+        let mut data = [0; 10];
+        io::Read::read_exact(&mut &host, &mut data).unwrap();
+        assert_eq!(&data, b"headerbody");
+    }
+
+    #[test]
+    fn buffered_channel_writes_large_data_directly() {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let syscalls = HostSyscalls::default();
+        let mut channel = BufferedChannel::with_capacity(8, 8, channel(&guest, syscalls.clone()));
+
+        channel.write_all(b"abc").unwrap();
+        channel.write_all(b"0123456789").unwrap();
+        assert_eq!(syscalls.writes.load(Ordering::Relaxed), 2);
+
+        let mut data = [0; 13];
+        io::Read::read_exact(&mut &host, &mut data).unwrap();
+        assert_eq!(&data, b"abc0123456789");
+    }
+
+    #[test]
+    fn buffered_channel_reads_ahead() {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let syscalls = HostSyscalls::default();
+        let mut channel = BufferedChannel::new(channel(&guest, syscalls.clone()));
+        io::Write::write_all(&mut &host, b"headerbody").unwrap();
+
+        let mut header = [0; 6];
+        let mut body = [0; 4];
+        channel.read_exact(&mut header).unwrap();
+        channel.read_exact(&mut body).unwrap();
+        assert_eq!((&header, &body), (b"header", b"body"));
+        assert_eq!(syscalls.reads.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn buffered_channel_reports_truncated_read() {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let mut channel = BufferedChannel::new(channel(&guest, HostSyscalls::default()));
+        io::Write::write_all(&mut &host, b"abc").unwrap();
+        drop(host);
+
+        let err = channel.read_exact(&mut [0; 5]).unwrap_err();
+        assert_eq!(
+            ChannelError::find(&err),
+            Some(ChannelError::Truncated { transferred: 3, expected: 5 })
+        );
+    }
+
+    #[test]
+    #[cfg(not(any(
+        feature = "channel_constant_request",
+        feature = "channel_probe_send",
+        feature = "channel_receive_only",
+        feature = "channel_echo",
+        feature = "channel_idle"
+    )))]
+    fn server_responds_to_requests() {
+        let (host, server) = spawn_server(
+            HostSyscalls { end_is_error: true, ..Default::default() },
+            Reverse::default(),
+        );
+        let mut client = client(&host);
+
+        assert_eq!(request(&mut client, 1, b"ping"), b"gnip");
+        assert_eq!(request(&mut client, 2, b"pong"), b"gnop");
+        stop(host, server);
+    }
+
+    #[test]
+    #[cfg(not(any(
+        feature = "channel_constant_request",
+        feature = "channel_probe_send",
+        feature = "channel_receive_only",
+        feature = "channel_echo",
+        feature = "channel_idle"
+    )))]
+    fn buffered_server_writes_each_response_at_once() {
+        let syscalls = HostSyscalls { end_is_error: true, ..Default::default() };
+        let (host, server) = spawn_server_with(syscalls.clone(), Reverse::default(), |channel| {
+            Box::new(BufferedChannel::new(channel))
+        });
+        let mut client = client(&host);
+
+        assert_eq!(request(&mut client, 1, b"ping"), b"gnip");
+        assert_eq!(syscalls.writes.load(Ordering::Relaxed), 1);
+        stop(host, server);
+    }
+
+    #[test]
+    #[cfg(not(any(
+        feature = "channel_constant_request",
+        feature = "channel_probe_send",
+        feature = "channel_receive_only",
+        feature = "channel_echo",
+        feature = "channel_idle"
+    )))]
+    fn server_skips_malformed_requests() {
+        let (host, server) = spawn_server(
+            HostSyscalls { end_is_error: true, ..Default::default() },
+            Reverse::default(),
+        );
+        let mut client = client(&host);
+
+        io::Write::write_all(&mut &host, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request(&mut client, 7, b"abc"), b"cba");
+        stop(host, server);
+    }
+
+    #[test]
+    #[cfg(not(any(
+        feature = "channel_constant_request",
+        feature = "channel_probe_send",
+        feature = "channel_receive_only",
+        feature = "channel_echo",
+        feature = "channel_idle"
+    )))]
+    fn server_waits_for_host_to_reconnect() {
+        let syscalls = HostSyscalls {
+            closed_reads: Arc::new(AtomicUsize::new(1)),
+            end_is_error: true,
+            ..Default::default()
+        };
+        let (host, server) = spawn_server(syscalls, Reverse::default());
+        let mut client = client(&host);
+
+        // The rest of a request the old host didn't finish sending.
+        io::Write::write_all(&mut &host, b"tail of a request").unwrap();
+        assert_eq!(request(&mut client, 3, b"again"), b"niaga");
+        stop(host, server);
+    }
+
+    #[test]
+    #[cfg(feature = "channel_constant_request")]
+    fn constant_request_mode_invokes_server_without_reading() {
+        let syscalls = HostSyscalls::default();
+        let (requests, received) = mpsc::channel();
+        // The server halts afterwards, so it's left running.
+        let (_host, _server) = spawn_server(syscalls.clone(), Reverse(Some(requests)));
+
+        assert_eq!(received.recv().unwrap(), CONSTANT_REQUEST_BODY);
+        assert_eq!(syscalls.reads.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "channel_probe_send")]
+    fn probe_send_mode_sends_probe_response() {
+        // The server halts afterwards, so it's left running.
+        let (host, _server) = spawn_server(HostSyscalls::default(), Reverse::default());
+
+        let response = client(&host).read_response().unwrap();
+        assert_eq!(response.invocation_id, PROBE_INVOCATION_ID);
+        assert_eq!(response.body, PROBE_BODY);
+    }
+
+    #[test]
+    #[cfg(feature = "channel_receive_only")]
+    fn receive_only_mode_reads_without_responding() {
+        let (requests, received) = mpsc::channel();
+        let (host, server) = spawn_server(
+            HostSyscalls { end_is_error: true, ..Default::default() },
+            Reverse(Some(requests)),
+        );
+        let mut client = client(&host);
+
+        for invocation_id in 0..3 {
+            client.write_request(RequestMessage { invocation_id, body: b"abc".to_vec() }).unwrap();
+        }
+        let responses = host.try_clone().unwrap();
+        host.shutdown(std::net::Shutdown::Write).unwrap();
+        stop(host, server);
+
+        let mut received_responses = Vec::new();
+        io::Read::read_to_end(&mut &responses, &mut received_responses).unwrap();
+        assert!(received_responses.is_empty());
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "channel_idle")]
+    fn idle_mode_leaves_the_channel_alone() {
+        let syscalls = HostSyscalls::default();
+        let (requests, received) = mpsc::channel();
+        // The server halts right away, so it's left running.
+        let (host, _server) = spawn_server(syscalls.clone(), Reverse(Some(requests)));
+
+        client(&host)
+            .write_request(RequestMessage { invocation_id: 1, body: b"abc".to_vec() })
+            .unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(syscalls.reads.load(Ordering::Relaxed), 0);
+        assert_eq!(syscalls.writes.load(Ordering::Relaxed), 0);
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "channel_echo")]
+    fn echo_mode_returns_request_body() {
+        let (requests, received) = mpsc::channel();
+        let (host, server) = spawn_server(
+            HostSyscalls { end_is_error: true, ..Default::default() },
+            Reverse(Some(requests)),
+        );
+        let mut client = client(&host);
+
+        assert_eq!(request(&mut client, 1, b"ping"), b"ping");
+        assert_eq!(request(&mut client, 2, b"pong"), b"pong");
+        assert!(received.try_recv().is_err());
+        stop(host, server);
+    }
+
+    /// Keeps the messages logged by each thread, so that tests can check
+    /// what reached the console.
+    struct CapturingLogger;
+
+    static LOGGED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Info
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                let thread = thread::current().name().unwrap_or_default().into();
+                LOGGED.lock().unwrap().push((thread, format!("{}", record.args())));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Returns what the current thread logged so far.
+    fn logged() -> Vec<String> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CapturingLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+        });
+        let thread = String::from(thread::current().name().unwrap_or_default());
+        let logged = LOGGED.lock().unwrap();
+        logged.iter().filter(|(from, _)| *from == thread).map(|(_, line)| line.clone()).collect()
+    }
+
+    fn reported_samples() -> Vec<String> {
+        logged().into_iter().filter(|line| line.starts_with("oak_sample request_ticks")).collect()
+    }
+
+    #[test]
+    fn samples_are_reported_in_batches() {
+        logged();
+        let mut stats = StaticSampleStore::<16>::new().unwrap();
+        let mut samples = SampleBatch::new();
+        for _ in 0..3 {
+            record_sample(&mut stats, &mut samples, &Timer::new_rdtsc());
+        }
+        assert!(reported_samples().is_empty());
+
+        samples.report();
+        let reported = reported_samples();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].split(' ').count(), 2 + 3, "{}", reported[0]);
+    }
+
+    #[test]
+    fn full_batch_is_reported() {
+        logged();
+        let mut samples = SampleBatch::new();
+        for sample in 0..SAMPLE_BATCH_SIZE as u64 + 1 {
+            samples.push(sample);
+        }
+
+        let reported = reported_samples();
+        assert_eq!(reported.len(), 1);
+        assert!(reported[0].ends_with(&format!(" {}", SAMPLE_BATCH_SIZE - 1)), "{}", reported[0]);
+        assert_eq!(samples.samples, [SAMPLE_BATCH_SIZE as u64]);
+    }
+
+    #[test]
+    fn samples_are_reported_with_the_first_request_after_the_interval() {
+        logged();
+        let mut stats = StaticSampleStore::<16>::new().unwrap();
+        let mut samples = SampleBatch::new();
+        record_sample(&mut stats, &mut samples, &Timer::new_rdtsc());
+        while samples.last_report.elapsed() < SAMPLE_REPORT_INTERVAL_TICKS {
+            core::hint::spin_loop();
+        }
+        assert!(reported_samples().is_empty());
+
+        record_sample(&mut stats, &mut samples, &Timer::new_rdtsc());
+        let reported = reported_samples();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].split(' ').count(), 2 + 2, "{}", reported[0]);
+        assert!(samples.samples.is_empty());
+    }
+
+    #[test]
+    fn remaining_samples_are_reported_when_the_server_ends() {
+        logged();
+        let mut samples = SampleBatch::new();
+        samples.push(5);
+        samples.push(7);
+        assert!(!samples.is_due());
+        drop(samples);
+
+        assert_eq!(reported_samples(), ["oak_sample request_ticks 5 7"]);
+    }
+
+    #[test]
+    fn frame_layout_matches_oak_channel() {
+        let (host, guest) = UnixStream::pair().unwrap();
+        let body = std::vec![0x5a; 2 * frame::MAX_SIZE];
+        client(&host)
+            .write_request(RequestMessage { invocation_id: 7, body: body.clone() })
+            .unwrap();
+        drop(host);
+
+        let mut channel = channel(&guest, HostSyscalls::default());
+        let mut header = [0; frame::HEADER_SIZE];
+        channel.read_exact(&mut header).unwrap();
+        assert!(is_message_start(&header));
+        let mut received = Vec::new();
+        loop {
+            let length = u32::from_le_bytes(header[..frame::LENGTH_SIZE].try_into().unwrap());
+            let flags = u16::from_le_bytes(header[frame::LENGTH_SIZE..].try_into().unwrap());
+            assert!((frame::HEADER_SIZE..=frame::MAX_SIZE).contains(&(length as usize)));
+            let start = received.len();
+            received.resize(start + length as usize - frame::HEADER_SIZE, 0);
+            channel.read_exact(&mut received[start..]).unwrap();
+            if flags & frame::FLAG_END != 0 {
+                break;
+            }
+            channel.read_exact(&mut header).unwrap();
+            assert!(!is_message_start(&header));
+        }
+        let (invocation_id, received) = received.split_at(frame::INVOCATION_ID_SIZE);
+        assert_eq!(invocation_id, 7u32.to_le_bytes());
+        assert_eq!(received, body);
+    }
+
+    #[test]
+    fn error_response_is_a_response_wrapper_with_status() {
+        let response = micro_rpc::ResponseWrapper::decode(
+            error_response(micro_rpc::StatusCode::InvalidArgument, "bad").as_slice(),
+        )
+        .unwrap();
+        let status = Result::<Vec<u8>, micro_rpc::Status>::from(response).unwrap_err();
+        assert_eq!(status.code, micro_rpc::StatusCode::InvalidArgument);
+        assert_eq!(status.message, "bad");
+    }
+}
@@ -44,12 +44,27 @@ BIOS_DEST="stage0_bin"
 KERNEL_DEST="oak_restricted_kernel_wrapper_virtio_console_channel_bin"
 INITRD_DEST="oak_orchestrator" 
 
+# Diagnostic mode of the guest's start_blocking_server (see ledger/channel.rs):
+# normal, constant_request, probe_send, receive_only, echo or idle.
+CHANNEL_MODE="${CHANNEL_MODE:-normal}"
+LEDGER_APP_BUILD_FLAGS=()
+case "$CHANNEL_MODE" in
+    normal) ;;
+    constant_request|probe_send|receive_only|echo|idle)
+        LEDGER_APP_BUILD_FLAGS+=("--@rules_rust//:extra_rustc_flag=--cfg=feature=\"channel_$CHANNEL_MODE\"")
+        ;;
+    *)
+        echo "❌ Error: unknown CHANNEL_MODE $CHANNEL_MODE" >&2
+        exit 1
+        ;;
+esac
+
 # Build and copy Ledger application
 (
     cd "$CFC_DIR"
     export PATH="$HOME/.nix-profile/bin:$PATH"
-    bazelisk build "$LEDGER_APP_TARGET"
-    SRC_PATH=$(bazelisk cquery "$LEDGER_APP_TARGET" --output=files)
+    bazelisk build "${LEDGER_APP_BUILD_FLAGS[@]}" "$LEDGER_APP_TARGET"
+    SRC_PATH=$(bazelisk cquery "${LEDGER_APP_BUILD_FLAGS[@]}" "$LEDGER_APP_TARGET" --output=files)
     if [ -z "$SRC_PATH" ]; then
         echo "❌ Error: bazelisk cquery failed to find path for $LEDGER_APP_TARGET" >&2
         exit 1
@@ -106,18 +121,32 @@ for f in "$LEDGER_APP_EXEC_PATH" "$LAUNCHER_EXEC_PATH" "$BIOS_PATH" "$KERNEL_PAT
 done
 echo "✅ All artifacts found."
 
-# Find an available port for the Ledger TEE
-LEDGER_HOST_PORT="46787" #$(python3 -c 'import socket; s=socket.socket(socket.AF_INET, socket.SOCK_STREAM); s.bind(("127.0.0.1", 0)); print(s.getsockname()[1]); s.close()')
+# Host and port for the launcher bridge. Set LEDGER_HOST_PORT=0 to let the launcher pick a
+# free port; the chosen address is written to $LEDGER_BRIDGE_ADDRESS_FILE. The bridge only
+# accepts local clients unless LEDGER_BRIDGE_HOST is set to a reachable address, e.g.
+# LEDGER_BRIDGE_HOST=0.0.0.0; it doesn't authenticate clients.
+LEDGER_BRIDGE_HOST="${LEDGER_BRIDGE_HOST:-127.0.0.1}"
+LEDGER_HOST_PORT="${LEDGER_HOST_PORT:-46787}"
+LEDGER_BRIDGE_ADDRESS_FILE="${LEDGER_BRIDGE_ADDRESS_FILE:-/tmp/ledger_bridge_address}"
 LEDGER_VM_PORT="8080" # Ledger app listens on 8080 inside VM
+LEDGER_BRIDGE_FLAGS=""
+case "$LEDGER_BRIDGE_HOST" in
+  127.*|"[::1]") ;;
+  *) LEDGER_BRIDGE_FLAGS="--bridge-allow-remote" ;;
+esac
 
 echo "Starting the launcher with all required components..."
-echo "The Ledger service will be available at http://127.0.0.1:$LEDGER_HOST_PORT"
+if [ "$LEDGER_HOST_PORT" = "0" ]; then
+    echo "The Ledger bridge address will be written to $LEDGER_BRIDGE_ADDRESS_FILE"
+else
+    echo "The Ledger bridge will be available at $LEDGER_BRIDGE_HOST:$LEDGER_HOST_PORT"
+fi
 echo "Press Ctrl+C to shut down the ledger service."
 
 # Execute the launcher
 ##                   oak_restricted_kernel_launcher   ==                     stage0_bin    oak_restricted_kernel_wrapper_virtio_console_channel_bin                                                                                                           
 ##                                                                                                                                     oak_orchestrator            ledger_enclave_app
-sudo RUST_LOG=debug "$LAUNCHER_EXEC_PATH"  --vmm-binary="$QEMU_PATH"     --bios-binary="$BIOS_PATH"     --kernel="$KERNEL_PATH"     --initrd="$INITRD_PATH"     --app-binary="$LEDGER_APP_EXEC_PATH"     --memory-size="8G"   #--gdb=1234 #used
+sudo RUST_LOG=debug "$LAUNCHER_EXEC_PATH"  --vmm-binary="$QEMU_PATH"     --bios-binary="$BIOS_PATH"     --kernel="$KERNEL_PATH"     --initrd="$INITRD_PATH"     --app-binary="$LEDGER_APP_EXEC_PATH"     --memory-size="8G"     --bridge-listen="$LEDGER_BRIDGE_HOST:$LEDGER_HOST_PORT" $LEDGER_BRIDGE_FLAGS     --bridge-address-file="$LEDGER_BRIDGE_ADDRESS_FILE"   || LAUNCHER_STATUS=$? #--gdb=1234 #used
 
 # sudo RUST_LOG=debug "$LAUNCHER_EXEC_PATH" --vmm-binary="$QEMU_PATH" --bios-binary="$BIOS_PATH" --kernel="$KERNEL_PATH" --initrd="$INITRD_PATH" --app-binary="$LEDGER_APP_EXEC_PATH" --memory-size="2G"
 
@@ -136,4 +165,10 @@ sudo RUST_LOG=debug "$LAUNCHER_EXEC_PATH"  --vmm-binary="$QEMU_PATH"     --bios-
 #   --app-binary="$LEDGER_APP_EXEC_PATH" \
 #   --memory-size="8G"
 
-echo "🎉 Ledger service has been shut down."
+# The launcher shuts the ledger down cleanly on Ctrl+C / SIGTERM and exits with
+# 128 + signal number (130 / 143) in that case.
+LAUNCHER_STATUS="${LAUNCHER_STATUS:-0}"
+case "$LAUNCHER_STATUS" in
+    0|130|143) echo "🎉 Ledger service has been shut down." ;;
+    *) echo "❌ Launcher exited with status $LAUNCHER_STATUS" >&2; exit "$LAUNCHER_STATUS" ;;
+esac
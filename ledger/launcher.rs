@@ -16,15 +16,11 @@
 
 use std::{
     fs,
-    io::{BufRead, BufReader, Read},
     net::Shutdown,
-    os::{
-        fd::AsRawFd,
-        unix::net::{self, UnixStream},
-    },
-    path::PathBuf,
+    os::{fd::AsRawFd, unix::net},
+    path::{Path, PathBuf},
     process::Stdio,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
@@ -32,11 +28,34 @@ use async_trait::async_trait;
 use clap::{Parser, ValueEnum};
 use command_fds::CommandFdExt;
 use log::info;
-use oak_proto_rust::oak::restricted_kernel::InitialData;
+use oak_proto_rust::oak::{
+    attestation::v1::{Endorsements, Evidence},
+    restricted_kernel::InitialData,
+};
 use prost::Message;
 
 use crate::channel::{Connector, ConnectorHandle};
 
+pub mod admin;
+pub mod boot;
+pub mod bridge;
+pub mod console;
+pub mod metrics;
+pub mod native;
+pub mod qmp;
+pub mod supervisor;
+pub mod transport;
+pub mod vmm;
+
+use boot::{BootPhase, BootTimeouts, BootTracker};
+use bridge::{Bridge, BridgeAddress};
+use console::{Console, ConsoleBuffer, ConsoleConfig, ConsoleSocket, FatalAction};
+use metrics::{Metrics, METRICS};
+use qmp::QmpClient;
+use supervisor::{RestartPolicy, Supervisor};
+use transport::{DeadlineSocket, HostSocket, Transport, VsockStream};
+use vmm::{AccelMode, Comms, GuestFds, Qemu, VmmBackend};
+
 #[derive(Debug, Clone, Default, PartialEq, ValueEnum)]
 pub enum InitialDataVersion {
     #[default]
@@ -44,25 +63,43 @@ pub enum InitialDataVersion {
     V1,
 }
 
-/// Represents parameters used for launching VM instances.
+/// How the guest is run.
+#[derive(Debug, Clone, Default, PartialEq, ValueEnum)]
+pub enum LaunchMode {
+    /// Run the guest in a VM using the VMM binary.
+    #[default]
+    Vm,
+    /// Run the guest directly as a host process, see [`native::NativeInstance`].
+    Native,
+}
+
+/// Represents parameters used for launching guest instances.
 #[derive(Parser, Clone, Debug, PartialEq)]
 pub struct Params {
-    /// Path to the VMM binary to execute.
+    /// How to run the guest.
+    #[arg(long, value_name = "MODE", default_value_t, value_enum)]
+    pub mode: LaunchMode,
+
+    /// Path to the VMM binary to execute. Required in VM mode.
     #[arg(long, value_parser = path_exists, value_name = "FILE")]
-    pub vmm_binary: PathBuf,
+    pub vmm_binary: Option<PathBuf>,
 
-    /// Path to the enclave binary to load into the VM.
+    /// Path to the enclave binary to load into the VM. Required in VM mode.
     #[arg(long, value_parser = path_exists, value_name = "FILE")]
-    pub kernel: PathBuf,
+    pub kernel: Option<PathBuf>,
+
+    /// Path to the binary to run as the guest in native mode.
+    #[arg(long, value_parser = path_exists, value_name = "FILE")]
+    pub native_binary: Option<PathBuf>,
 
     /// Path to the Oak Functions application binary to be loaded into the
     /// enclave.
     #[arg(long, value_parser = path_exists, value_name = "FILE")]
     pub app_binary: Option<PathBuf>,
 
-    /// Path to the BIOS image to use.
+    /// Path to the BIOS image to use. Required in VM mode.
     #[arg(long, value_parser = path_exists, value_name = "FILE")]
-    pub bios_binary: PathBuf,
+    pub bios_binary: Option<PathBuf>,
 
     /// Port to use for debugging with gdb
     #[arg(long, value_name = "PORT")]
@@ -73,19 +110,168 @@ pub struct Params {
     #[arg(long)]
     pub memory_size: Option<String>,
 
-    /// Path to the initrd image to use.
+    /// Path to the initrd image to use. Required in VM mode.
     #[arg(long, value_parser = path_exists, requires_all = &["kernel"], value_name = "FILE")]
-    pub initrd: PathBuf,
+    pub initrd: Option<PathBuf>,
 
     /// Pass the specified host PCI device through to the virtual machine using
     /// VFIO.
     #[arg(long, value_name = "ADDRESS")]
     pub pci_passthrough: Option<String>,
 
+    /// Accelerator for the VM. `auto` uses KVM if `/dev/kvm` is accessible and
+    /// falls back to TCG otherwise.
+    #[arg(long, value_name = "ACCEL", default_value_t, value_enum)]
+    pub accel: AccelMode,
+
+    /// CPU model to expose to the VM.
+    #[arg(long, value_name = "MODEL", default_value = vmm::DEFAULT_CPU_MODEL)]
+    pub cpu_model: String,
+
+    /// Machine type (and machine options) for the VM. Machine types other than
+    /// `microvm` get the PCI variants of the virtio devices.
+    #[arg(long, value_name = "MACHINE", default_value = vmm::DEFAULT_MACHINE_TYPE)]
+    pub machine_type: String,
+
+    /// Number of vCPUs to give to the VM.
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u16).range(1..))]
+    pub vcpus: Option<u16>,
+
+    /// How the launcher talks to the guest.
+    #[arg(long, value_name = "TRANSPORT", default_value_t, value_enum)]
+    pub transport: Transport,
+
+    /// CID to give the guest with `--transport=vsock`. A free one is picked if
+    /// not set.
+    #[arg(long, value_name = "CID", value_parser = parse_guest_cid)]
+    pub guest_cid: Option<u32>,
+
+    /// Port the guest listens on with `--transport=vsock`.
+    #[arg(long, value_name = "PORT", default_value_t = transport::DEFAULT_VSOCK_PORT)]
+    pub vsock_port: u32,
+
     /// Use the V1 format for initial data loading (binary + endorsements in
     /// proto).
     #[arg(long, value_name = "INITIAL_DATA_VERSION", default_value_t, value_enum)]
     pub initial_data_version: InitialDataVersion,
+
+    /// Path to the endorsements of the application binary, as an encoded
+    /// `oak.attestation.v1.Endorsements` proto. Only supported with the V1 initial
+    /// data format.
+    #[arg(long, value_parser = path_exists, requires = "app_binary", value_name = "FILE")]
+    pub app_endorsements: Option<PathBuf>,
+
+    /// Address for the bridge to listen on for messages to forward to the guest:
+    /// `HOST:PORT` (IPv4 or IPv6, e.g. `[::1]:46787`) or `unix:PATH`. May be given
+    /// multiple times. Port 0 picks a free port; the bound addresses are printed
+    /// to stdout. Addresses other than loopback ones need
+    /// `--bridge-allow-remote`.
+    #[arg(long, value_parser = parse_bridge_address, value_name = "ADDRESS", default_value = bridge::DEFAULT_ADDRESS)]
+    pub bridge_listen: Vec<BridgeAddress>,
+
+    /// Allows the bridge to listen on addresses other hosts can reach, such as
+    /// `0.0.0.0:46787`. The bridge doesn't authenticate clients.
+    #[arg(long)]
+    pub bridge_allow_remote: bool,
+
+    /// File to write the bound bridge addresses to, one per line.
+    #[arg(long, value_name = "FILE")]
+    pub bridge_address_file: Option<PathBuf>,
+
+    /// File to write the attestation evidence received from the guest to, as an
+    /// encoded `oak.attestation.v1.Evidence` proto. Requires the
+    /// `exchange_evidence` feature.
+    #[arg(long, value_name = "FILE")]
+    pub evidence_output: Option<PathBuf>,
+
+    /// File to write the guest console output to, in addition to stdout.
+    #[arg(long, value_name = "FILE")]
+    pub console_log: Option<PathBuf>,
+
+    /// Size in bytes at which the console log file is rotated.
+    #[arg(long, value_name = "BYTES", default_value_t = 10 * 1024 * 1024)]
+    pub console_log_max_bytes: u64,
+
+    /// Number of rotated console log files to keep.
+    #[arg(long, value_name = "COUNT", default_value_t = 5)]
+    pub console_log_files: usize,
+
+    /// Unix domain socket to serve the guest console on. Attach with
+    /// `launcher attach <SOCKET>` or `socat - UNIX-CONNECT:<SOCKET>`.
+    #[arg(long, value_name = "SOCKET")]
+    pub console_socket: Option<PathBuf>,
+
+    /// Number of recent console lines to keep in memory.
+    #[arg(long, value_name = "LINES", default_value_t = 1000)]
+    pub console_buffer_lines: usize,
+
+    /// Extra console text that marks a fatal guest error, in addition to the
+    /// built-in panic signatures. Matched case-insensitively.
+    #[arg(long, value_name = "TEXT")]
+    pub fatal_pattern: Vec<String>,
+
+    /// What to do when the guest console shows a panic or fatal error.
+    #[arg(long, value_name = "ACTION", default_value_t, value_enum)]
+    pub on_guest_fatal: FatalAction,
+
+    /// Directory to dump the guest memory to when the guest console shows a
+    /// panic or fatal error. Needs a VMM with QMP support.
+    #[arg(long, value_name = "DIR")]
+    pub crash_dump_dir: Option<PathBuf>,
+
+    /// Address to serve the admin HTTP API on: a loopback `HOST:PORT` or
+    /// `unix:PATH`. The API is disabled unless set.
+    #[arg(long, value_parser = admin::parse_address, value_name = "ADDRESS")]
+    pub admin_listen: Option<BridgeAddress>,
+
+    /// What to do when the guest exits.
+    #[arg(long, value_name = "POLICY", default_value_t, value_enum)]
+    pub restart_policy: RestartPolicy,
+
+    /// Delay before restarting the guest, in milliseconds. Doubles with every
+    /// consecutive restart.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 1000)]
+    pub restart_backoff_ms: u64,
+
+    /// Maximum delay before restarting the guest, in milliseconds.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 60_000)]
+    pub restart_backoff_max_ms: u64,
+
+    /// How long the guest has to stay up, in milliseconds, for the next
+    /// restart to start over at `--restart-backoff-ms`.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 60_000)]
+    pub restart_healthy_ms: u64,
+
+    /// Maximum number of times to restart the guest. Unlimited if not set.
+    #[arg(long, value_name = "COUNT")]
+    pub max_restarts: Option<u32>,
+
+    /// How long the guest gets to boot once it's started, in milliseconds. With
+    /// vsock that's until it accepts the connection; over a socketpair the time
+    /// left is added to `--load-app-timeout-ms`, since the guest only starts
+    /// reading the application once it has booted.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 30_000)]
+    pub spawn_timeout_ms: u64,
+
+    /// How long sending the application to the guest may take, in
+    /// milliseconds.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 30_000)]
+    pub load_app_timeout_ms: u64,
+
+    /// How long the guest gets to send its attestation evidence once it has
+    /// the application, in milliseconds.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 30_000)]
+    pub evidence_timeout_ms: u64,
+
+    /// How long to wait for in-flight bridge requests to complete when shutting
+    /// down, in milliseconds.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 10_000)]
+    pub shutdown_timeout_ms: u64,
+
+    /// Print the VMM command that would be executed and exit without starting
+    /// the guest.
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
 /// Checks if file with a given path exists.
@@ -98,11 +284,31 @@ fn path_exists(s: &str) -> Result<PathBuf, String> {
     }
 }
 
+/// Parses a bridge listen address.
+fn parse_bridge_address(s: &str) -> Result<BridgeAddress, String> {
+    s.parse()
+}
+
+/// How long QEMU gets to quit after being asked to over QMP before it's
+/// killed.
+const QMP_QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parses a guest CID, rejecting the ones reserved for the host and for "any".
+fn parse_guest_cid(s: &str) -> Result<u32, String> {
+    let cid: u32 = s.parse().map_err(|err: std::num::ParseIntError| err.to_string())?;
+    if cid < transport::FIRST_GUEST_CID || cid == u32::MAX {
+        return Err(format!("CID {cid} is reserved"));
+    }
+    Ok(cid)
+}
+
 /// Represents an a guest instance launched in virtualized environment.
 pub struct Instance {
     guest_console: net::UnixStream,
-    host_socket: net::UnixStream,
+    host_socket: HostSocket,
     instance: tokio::process::Child,
+    evidence: Option<Evidence>,
+    qmp: Option<QmpClient>,
 }
 
 impl Instance {
@@ -112,23 +318,46 @@ impl Instance {
     /// The instance is started with the `kill_on_drop` flag set, so you'll need
     /// to hold onto the `Instance` for as long as you'd like the task to keep
     /// running.
-    pub fn start(params: Params, guest_console: net::UnixStream) -> Result<Self> {
-        let app_bytes = if let Some(app_binary) = params.app_binary {
-            let bytes = fs::read(&app_binary).with_context(|| {
-                format!("couldn't read application binary {}", app_binary.display())
-            })?;
-            log::info!(
-                "read application binary from disk {} ({} bytes)",
-                app_binary.display(),
-                bytes.len()
-            );
-            Some(bytes)
-        } else {
-            None
-        };
+    ///
+    /// The boot phases are recorded in `boot`.
+    pub fn start(
+        params: Params,
+        guest_console: net::UnixStream,
+        boot: &BootTracker,
+    ) -> Result<Self> {
+        let vmm = Qemu::from_params(&params)?;
+        Self::start_with_vmm(&vmm, params, guest_console, boot)
+    }
 
-        let mut cmd = tokio::process::Command::new(params.vmm_binary);
-        let (guest_socket, mut host_socket) = net::UnixStream::pair()?;
+    /// Like [`Instance::start`], but runs the guest with the given VMM backend
+    /// instead of QEMU.
+    pub fn start_with_vmm(
+        vmm: &dyn VmmBackend,
+        params: Params,
+        guest_console: net::UnixStream,
+        boot: &BootTracker,
+    ) -> Result<Self> {
+        let timeouts = BootTimeouts::from_params(&params);
+        let initial_data_bytes = initial_data(&params)?;
+
+        let mut cmd = tokio::process::Command::new(vmm.binary());
+        // With vsock the guest is connected to once it's running; otherwise the VMM
+        // gets one end of a socketpair.
+        let (comms, guest_socket, host_socket) = match params.transport {
+            Transport::VirtioSerial => {
+                let (guest_socket, host_socket) = net::UnixStream::pair()?;
+                let comms = Comms::VirtioSerial(guest_socket.as_raw_fd());
+                (comms, Some(guest_socket), Some(HostSocket::Unix(host_socket)))
+            }
+            Transport::Vsock => {
+                let cid = match params.guest_cid {
+                    Some(cid) => cid,
+                    None => transport::allocate_cid()?,
+                };
+                (Comms::Vsock { cid }, None, None)
+            }
+        };
+        let qmp_sockets = if vmm.supports_qmp() { Some(net::UnixStream::pair()?) } else { None };
 
         // Clone the console stream so we can use it in the child process and also
         // return it from this method.
@@ -136,142 +365,205 @@ impl Instance {
 
         // Extract the raw file descriptor numbers from the streams before passing them
         // to the child process, since that takes ownership of them.
-        let guest_console_fd = guest_console.as_raw_fd();
-        let guest_socket_fd = guest_socket.as_raw_fd();
+        let fds = GuestFds {
+            console: guest_console.as_raw_fd(),
+            comms,
+            qmp: qmp_sockets.as_ref().map(|(vmm_qmp, _)| vmm_qmp.as_raw_fd()),
+        };
+        let (vmm_qmp, qmp) = qmp_sockets.unzip();
 
         cmd.stderr(Stdio::inherit());
         cmd.stdin(Stdio::null());
         cmd.stdout(Stdio::inherit());
-        cmd.preserved_fds(vec![guest_console.into(), guest_socket.into()]);
-
-        // Construct the command-line arguments for `qemu`.
-        cmd.arg("-enable-kvm");
-        // Log guest errors and other interesting events to stderr.
-        cmd.args(["-d", "int,unimp,guest_errors"]);
-        // Needed to expose advanced CPU features. Specifically RDRAND which is required
-        // for remote attestation.
-        cmd.args(["-cpu", "IvyBridge-IBRS"]);
-        // Set memory size if given.
-        if let Some(memory_size) = params.memory_size {
-            cmd.args(["-m", &memory_size]);
+        cmd.preserved_fds(
+            std::iter::once(guest_console.into())
+                .chain(guest_socket.map(Into::into))
+                .chain(vmm_qmp.map(Into::into))
+                .collect(),
+        );
+        cmd.args(vmm.args(fds));
+        cmd.kill_on_drop(true);
+
+        info!("executing: {}", vmm::command_line(vmm, fds));
+
+        let instance = cmd.spawn()?;
+
+        let mut host_socket = match (host_socket, comms) {
+            (Some(host_socket), _) => host_socket,
+            (None, Comms::Vsock { cid }) => HostSocket::Vsock(VsockStream::connect_with_retry(
+                cid,
+                params.vsock_port,
+                boot.deadline(timeouts.spawning),
+            )?),
+            (None, Comms::VirtioSerial(_)) => unreachable!("virtio-serial has a host socket"),
         };
-        // Disable a bunch of hardware we don't need.
-        cmd.arg("-nodefaults");
-        cmd.arg("-nographic");
-        // If the VM restarts, don't restart it (we're not expecting any restarts so any
-        // restart should be treated as a failure)
-        cmd.arg("-no-reboot");
-        // Use the `microvm` machine as the basis, and ensure ACPI is enabled.
-        cmd.args(["-machine", "microvm,acpi=on"]);
-        // Route first serial port to console.
-        cmd.args(["-chardev", format!("socket,id=consock,fd={guest_console_fd}").as_str()]);
-        cmd.args(["-serial", "chardev:consock"]);
-        // Add the virtio device.
-        cmd.args(["-chardev", format!("socket,id=commsock,fd={guest_socket_fd}").as_str()]);
-        cmd.args(["-device", "virtio-serial-device,max_ports=1"]);
-        cmd.args(["-device", "virtconsole,chardev=commsock"]);
-        if let Some(pci_passthrough) = params.pci_passthrough {
-            cmd.args(["-device", format!("vfio-pci,host={pci_passthrough}").as_str()]);
-        }
-        // Use stage0 as the BIOS.
-        cmd.args(["-bios", params.bios_binary.into_os_string().into_string().unwrap().as_str()]);
-        // stage0 accoutrements: kernel that's compatible with the linux boot protocol
-        cmd.args(["-kernel", params.kernel.into_os_string().into_string().unwrap().as_str()]);
-
-        if let Some(gdb_port) = params.gdb {
-            // Listen for a gdb connection on the provided port and wait for debugger before
-            // booting
-            cmd.args(["-gdb", format!("tcp::{gdb_port}").as_str()]);
-            cmd.arg("-S");
-        }
 
-        cmd.args(["-initrd", params.initrd.into_os_string().into_string().unwrap().as_str()]);
+        let evidence = match initial_data_bytes {
+            Some(initial_data_bytes) => load_guest(
+                &mut host_socket,
+                &initial_data_bytes,
+                params.evidence_output.as_deref(),
+                boot,
+                &timeouts,
+                params.transport == Transport::Vsock,
+            )?,
+            None => None,
+        };
 
-        cmd.kill_on_drop(true);
+        Ok(Self {
+            guest_console: guest_console_clone,
+            host_socket,
+            instance,
+            evidence,
+            qmp: qmp.map(QmpClient::new).transpose()?,
+        })
+    }
+}
 
-        info!("executing: {:?}", cmd);
+/// Returns the command line that [`Instance::start`], or in native mode
+/// [`native::NativeInstance::start`], would execute for the given parameters,
+/// without starting anything.
+pub fn dry_run_command(params: &Params) -> Result<String> {
+    if params.mode == LaunchMode::Native {
+        return native::command_line(params);
+    }
+    let vmm = Qemu::from_params(params)?;
+    // The descriptor numbers differ from run to run; open real sockets so that at
+    // least they're numbers a launch from this process could plausibly get.
+    let (console, comms) = net::UnixStream::pair()?;
+    let (qmp, _) = net::UnixStream::pair()?;
+    let fds = GuestFds {
+        console: console.as_raw_fd(),
+        comms: match params.transport {
+            Transport::VirtioSerial => Comms::VirtioSerial(comms.as_raw_fd()),
+            // A launch picks a free CID instead if none is configured.
+            Transport::Vsock => {
+                Comms::Vsock { cid: params.guest_cid.unwrap_or(transport::FIRST_GUEST_CID) }
+            }
+        },
+        qmp: vmm.supports_qmp().then(|| qmp.as_raw_fd()),
+    };
+    Ok(vmm::command_line(&vmm, fds))
+}
 
-        let instance = cmd.spawn()?;
+/// Reads the application binary and, if given, its endorsements from disk and
+/// builds the initial data to send to the guest in the requested format.
+///
+/// Returns `None` if there's no application binary to load.
+fn initial_data(params: &Params) -> Result<Option<Vec<u8>>> {
+    let Some(app_binary) = &params.app_binary else {
+        return Ok(None);
+    };
+    let app_bytes = fs::read(app_binary)
+        .with_context(|| format!("couldn't read application binary {}", app_binary.display()))?;
+    log::info!(
+        "read application binary from disk {} ({} bytes)",
+        app_binary.display(),
+        app_bytes.len()
+    );
+
+    let endorsement_bytes = if let Some(app_endorsements) = &params.app_endorsements {
+        if params.initial_data_version != InitialDataVersion::V1 {
+            anyhow::bail!("application endorsements require the V1 initial data format");
+        }
+        load_endorsements(app_endorsements)?
+    } else {
+        Vec::new()
+    };
 
-        if let Some(app_bytes) = app_bytes {
-            let initial_data_bytes = match params.initial_data_version {
-                InitialDataVersion::V0 => app_bytes,
-                InitialDataVersion::V1 => {
-                    let initial_data =
-                        InitialData { application_bytes: app_bytes, endorsement_bytes: Vec::new() };
-
-                    let mut initial_data_bytes =
-                        oak_restricted_kernel_interface::initial_data::INITIAL_DATA_V1_HEADER
-                            .to_vec();
-                    initial_data.encode(&mut initial_data_bytes)?;
-                    initial_data_bytes
-                }
-            };
+    let initial_data_bytes = match params.initial_data_version {
+        InitialDataVersion::V0 => app_bytes,
+        InitialDataVersion::V1 => {
+            let initial_data = InitialData { application_bytes: app_bytes, endorsement_bytes };
 
-            // The code below is all sync, but we need some reasonable deadlines otherwise
-            // we might just get stuck if the qemu process exits.
-            host_socket.set_read_timeout(Some(Duration::from_secs(30)))?;
-
-            oak_channel::basic_framed::send_raw(&mut host_socket, &initial_data_bytes)
-                .context("failed to send application")?;
-            #[cfg(feature = "exchange_evidence")]
-            let _evidence = oak_channel::basic_framed::receive_raw(&mut host_socket)
-                .context("failed to receive attestion evidence")?;
-
-            host_socket.set_read_timeout(None)?;
-
-            // Clone the socket for the listening thread.
-            let mut host_socket_clone = host_socket.try_clone()
-                .context("failed to clone host socket")?;
-
-            // Spawn a new thread to listen for incoming connections on the host and forward them to the guest.
-            std::thread::spawn(move || {
-                // Listen on the specified port on the host. This corresponds to the port forwarded from the guest in the QEMU settings.
-                let listener = match std::net::TcpListener::bind("0.0.0.0:46787") {
-                    Ok(listener) => listener,
-                    Err(e) => {
-                        log::error!("Failed to bind to port 46787: {:?}", e);
-                        return;
-                    }
-                };
-                info!("Listening on 127.0.0.1:46787 for messages to forward to the guest.");
-
-                // Accept connections and forward the data.
-                for stream in listener.incoming() {
-                    match stream {
-                        Ok(mut stream) => {
-                            info!("Accepted connection on port 46787.");
-                            let mut buffer = Vec::new();
-                            match stream.read_to_end(&mut buffer) {
-                                Ok(size) => {
-                                    if size > 0 {
-                                        info!("Read {} bytes from TCP stream, forwarding to guest.", size);
-                                        // Print the received message content as a lossy UTF-8 string for debugging.
-                                        info!("Message content: {}", String::from_utf8_lossy(&buffer));
-                                        // Also print the raw bytes in hexadecimal for detailed debugging.
-                                        info!("Message content (hex): {:x?}", buffer);
-                                        if let Err(e) =
-                                            oak_channel::basic_framed::send_raw(&mut host_socket_clone, &buffer)
-                                        {
-                                            log::error!("Failed to forward message to guest: {:?}", e);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    log::error!("Failed to read from incoming TCP connection: {:?}", e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("Failed to accept incoming TCP connection: {:?}", e);
-                        }
-                    }
-                }
-            });
+            let mut initial_data_bytes =
+                oak_restricted_kernel_interface::initial_data::INITIAL_DATA_V1_HEADER.to_vec();
+            initial_data.encode(&mut initial_data_bytes)?;
+            initial_data_bytes
         }
+    };
+    Ok(Some(initial_data_bytes))
+}
 
-        Ok(Self { guest_console: guest_console_clone, host_socket, instance })
+/// Sends the initial data to a freshly started guest and, if evidence exchange
+/// is enabled, receives its attestation evidence.
+///
+/// `booted` says whether the guest has shown that it's up by accepting a
+/// connection. If it hasn't, it may still be booting, and sending the initial
+/// data also gets what's left of the spawning timeout.
+fn load_guest(
+    host_socket: &mut HostSocket,
+    initial_data_bytes: &[u8],
+    evidence_output: Option<&Path>,
+    boot: &BootTracker,
+    timeouts: &BootTimeouts,
+    booted: bool,
+) -> Result<Option<Evidence>> {
+    // The code below is all sync, but we need some reasonable deadlines otherwise
+    // we might just get stuck if the guest process exits.
+    let boot_time_left = if booted {
+        Duration::ZERO
+    } else {
+        boot.deadline(timeouts.spawning).saturating_duration_since(Instant::now())
+    };
+    boot.enter(BootPhase::LoadingApp);
+    let deadline = boot.deadline(timeouts.loading_app + boot_time_left);
+    if let Err(err) = oak_channel::basic_framed::send_raw(
+        &mut DeadlineSocket::new(host_socket, deadline),
+        initial_data_bytes,
+    ) {
+        Metrics::increment(&METRICS.send_raw_failures);
+        return Err(err).context("failed to send application");
     }
+
+    #[cfg(feature = "exchange_evidence")]
+    let evidence = {
+        boot.enter(BootPhase::AwaitingEvidence);
+        let deadline = boot.deadline(timeouts.awaiting_evidence);
+        Some(receive_evidence(&mut DeadlineSocket::new(host_socket, deadline), evidence_output)?)
+    };
+    #[cfg(not(feature = "exchange_evidence"))]
+    let evidence = {
+        // `launch` rejects an evidence output when there's no evidence to write.
+        debug_assert!(evidence_output.is_none());
+        None
+    };
+
+    Ok(evidence)
+}
+
+/// Reads the application endorsements from disk, checking that they decode as an
+/// `Endorsements` proto before they're handed to the guest.
+fn load_endorsements(path: &Path) -> Result<Vec<u8>> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("couldn't read application endorsements {}", path.display()))?;
+    Endorsements::decode(bytes.as_slice())
+        .with_context(|| format!("couldn't decode application endorsements {}", path.display()))?;
+    log::info!(
+        "read application endorsements from disk {} ({} bytes)",
+        path.display(),
+        bytes.len()
+    );
+    Ok(bytes)
+}
+
+/// Receives the attestation evidence from the guest and, if requested, writes it
+/// to a file.
+#[cfg(feature = "exchange_evidence")]
+fn receive_evidence(
+    host_socket: &mut DeadlineSocket,
+    evidence_output: Option<&Path>,
+) -> Result<Evidence> {
+    let evidence_bytes = oak_channel::basic_framed::receive_raw(host_socket)
+        .context("failed to receive attestion evidence")?;
+    let evidence = Evidence::decode(evidence_bytes.as_slice())
+        .context("failed to decode attestation evidence")?;
+    log::info!("received attestation evidence ({} bytes)", evidence_bytes.len());
+    if let Some(evidence_output) = evidence_output {
+        fs::write(evidence_output, &evidence_bytes)
+            .with_context(|| format!("couldn't write evidence to {}", evidence_output.display()))?;
+    }
+    Ok(evidence)
 }
 
 #[async_trait]
@@ -283,6 +575,23 @@ impl GuestInstance for Instance {
 
     async fn kill(mut self: Box<Self>) -> Result<std::process::ExitStatus> {
         info!("killing guest instance; cleaning up and shutting down");
+        if let Some(qmp) = self.qmp.clone() {
+            let quit = async {
+                // QEMU may close the connection before it replies.
+                if let Err(err) = qmp.quit().await {
+                    log::debug!("{:?}", err);
+                }
+                self.instance.wait().await.map_err(anyhow::Error::from)
+            };
+            match tokio::time::timeout(QMP_QUIT_TIMEOUT, quit).await {
+                Ok(Ok(status)) => {
+                    self.guest_console.shutdown(Shutdown::Both)?;
+                    return Ok(status);
+                }
+                Ok(Err(err)) => log::warn!("failed to wait for QEMU to quit: {:?}", err),
+                Err(_) => log::warn!("QEMU didn't quit within {:?}", QMP_QUIT_TIMEOUT),
+            }
+        }
         self.guest_console.shutdown(Shutdown::Both)?;
         self.instance.start_kill()?;
         self.wait().await
@@ -292,6 +601,18 @@ impl GuestInstance for Instance {
         info!("connecting to guest instance");
         Ok(Box::new(self.host_socket.try_clone()?))
     }
+
+    fn evidence(&self) -> Option<&Evidence> {
+        self.evidence.as_ref()
+    }
+
+    fn qmp(&self) -> Option<&QmpClient> {
+        self.qmp.as_ref()
+    }
+
+    fn pid(&self) -> Option<u32> {
+        self.instance.id()
+    }
 }
 
 /// Defines the interface of a launched guest instance. Standardizes the
@@ -307,40 +628,118 @@ pub trait GuestInstance {
 
     /// Creates a channel to communicate with the guest instance.
     async fn connect(&self) -> Result<Box<dyn oak_channel::Channel>>;
+
+    /// Returns the attestation evidence the guest sent during startup, if any.
+    fn evidence(&self) -> Option<&Evidence> {
+        None
+    }
+
+    /// Returns the process ID of the guest (or its VMM), if it is running.
+    fn pid(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns the client for the VMM's QMP monitor, if it has one.
+    fn qmp(&self) -> Option<&QmpClient> {
+        None
+    }
+
+    /// Returns the recent guest console output, if it is being captured.
+    fn console(&self) -> Option<&ConsoleBuffer> {
+        None
+    }
 }
 
 /// Launches a new guest instance in given mode.
+///
+/// The returned instance restarts the guest according to the restart policy.
+/// The returned [`ConnectorHandle`] talks to the first guest only; the bridge
+/// follows restarts.
 pub async fn launch(
     params: Params,
 ) -> Result<(Box<dyn GuestInstance>, ConnectorHandle), Box<dyn std::error::Error>> {
-    // Provide a way for the launched instance to send logs
-    let guest_writer: UnixStream = {
-        // Create two linked consoles. Technically both can read/write, but we'll
-        // use them as a one way channel.
-        let (console_writer, console_receiver) = UnixStream::pair()?;
-
-        // Log everything sent by the writer.
-        tokio::spawn(async {
-            let mut reader = BufReader::new(console_receiver);
-
-            let mut line = String::new();
-            while reader.read_line(&mut line).expect("couldn't read line") > 0 {
-                // remove the new line character
-                line.pop();
-                log::info!("console: {:?}", line);
-                line.clear();
-            }
-        });
+    if cfg!(not(feature = "exchange_evidence")) && params.evidence_output.is_some() {
+        return Err("--evidence-output requires the exchange_evidence feature".into());
+    }
+    if !params.bridge_allow_remote {
+        if let Some(address) = params.bridge_listen.iter().find(|address| !address.is_local()) {
+            return Err(format!(
+                "bridge address {address} can be reached from other hosts; pass \
+                 --bridge-allow-remote to listen on it anyway"
+            )
+            .into());
+        }
+    }
 
-        console_writer
+    let bridge = if params.app_binary.is_some() {
+        Some(Bridge::start(&params.bridge_listen, params.bridge_address_file.as_deref()).await?)
+    } else {
+        None
     };
 
+    let console = Console::new(&ConsoleConfig::from_params(&params))?;
+    let console_socket = params
+        .console_socket
+        .as_deref()
+        .map(|path| ConsoleSocket::bind(&console, path))
+        .transpose()?;
+
     log::info!("launching instance");
 
-    let guest_instance = Box::new(Instance::start(params, guest_writer)?);
+    let boot = BootTracker::default();
+    let (guest_instance, connector_handle) = start_guest(params.clone(), &console, &boot).await?;
+    if let Some(bridge) = &bridge {
+        bridge.connect_guest(connector_handle.clone());
+    }
 
-    let channel = guest_instance.connect().await?;
-    let connector_handle = Connector::spawn(channel);
+    let admin_listen = params.admin_listen.clone();
+    let mut supervisor =
+        Supervisor::new(params, guest_instance, bridge, console, console_socket, boot);
+    if let Some(address) = &admin_listen {
+        supervisor.serve_admin(address).await?;
+    }
 
-    Ok((guest_instance, connector_handle))
+    Ok((Box::new(supervisor), connector_handle))
+}
+
+/// Starts a guest in the configured mode, with its console captured by the
+/// given console, and connects to it. The boot phases are recorded in `boot`,
+/// which ends up either `Ready` or `Failed`.
+async fn start_guest(
+    params: Params,
+    console: &Console,
+    boot: &BootTracker,
+) -> Result<(Box<dyn GuestInstance + Send + Sync>, ConnectorHandle)> {
+    boot.begin();
+    let result = async {
+        // Provide a way for the launched instance to send logs
+        let guest_writer = console.attach().await?;
+
+        // Starting the guest blocks until it has loaded the application, so keep it
+        // off the runtime's worker threads.
+        let boot = boot.clone();
+        let guest_instance =
+            tokio::task::spawn_blocking(move || -> Result<Box<dyn GuestInstance + Send + Sync>> {
+                Ok(match params.mode {
+                    LaunchMode::Vm => Box::new(Instance::start(params, guest_writer, &boot)?),
+                    LaunchMode::Native => {
+                        Box::new(native::NativeInstance::start(params, guest_writer, &boot)?)
+                    }
+                })
+            })
+            .await
+            .context("guest startup task failed")??;
+
+        let channel = guest_instance.connect().await?;
+        let connector_handle = Connector::spawn(channel);
+
+        Ok((guest_instance, connector_handle))
+    }
+    .await;
+
+    match &result {
+        Ok(_) => boot.enter(BootPhase::Ready),
+        Err(err) => boot.fail(err),
+    }
+    result
 }
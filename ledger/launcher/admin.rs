@@ -0,0 +1,559 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/admin.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Local HTTP API for inspecting and controlling a running launcher.
+//!
+//! The API has no authentication, so it only listens on loopback addresses or
+//! Unix sockets. To keep web pages the operator has open from reaching it
+//! through the browser, requests that carry an `Origin` header or name a host
+//! other than a loopback address in `Host` are refused. Each connection carries
+//! a single request:
+//!
+//! | Request            | Response                                              |
+//! |--------------------|-------------------------------------------------------|
+//! | `GET /status`      | JSON: guest and VM state, boot phases, PID, uptimes,  |
+//! |                    | bridge                                                |
+//! | `GET /evidence`    | The encoded `oak.attestation.v1.Evidence`, or 404     |
+//! | `GET /console`     | The last `?lines=N` (default 100) console lines       |
+//! | `GET /stats`       | JSON: bridge connection and traffic counters          |
+//! | `GET /metrics`     | Prometheus metrics, see [`super::metrics`]            |
+//! | `POST /pause`      | Pauses the VM's vCPUs over QMP                        |
+//! | `POST /resume`     | Resumes a paused VM                                   |
+//! | `POST /reset`      | Resets the VM over QMP, as if its reset button was    |
+//! |                    | pressed                                               |
+//! | `POST /restart`    | Restarts the guest, outside the restart budget        |
+//! | `POST /shutdown`   | Shuts the launcher down like `SIGTERM` does, but      |
+//! |                    | exiting with code 0                                   |
+
+use std::{
+    net::IpAddr,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{mpsc, watch},
+    task::{JoinHandle, JoinSet},
+};
+
+use super::{
+    boot::{BootPhase, BootTracker},
+    bridge::{BridgeAddress, BridgeStats, Connection, Listener},
+    console::{format_timestamp, ConsoleBuffer},
+    metrics,
+    qmp::{QmpClient, VmStatus},
+};
+
+/// Console lines returned by `GET /console` unless `lines` is given.
+const DEFAULT_CONSOLE_LINES: usize = 100;
+
+/// Longest request head, i.e. request line and headers, that is accepted.
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// Longest request body that is accepted. None of the endpoints use the body.
+const MAX_REQUEST_BODY: u64 = 64 * 1024;
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `GET /status` waits for QEMU to report the VM state.
+const QMP_STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Lifecycle state of the supervised guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestState {
+    Running,
+    Restarting,
+    Stopped,
+}
+
+impl GuestState {
+    fn as_str(self) -> &'static str {
+        match self {
+            GuestState::Running => "running",
+            GuestState::Restarting => "restarting",
+            GuestState::Stopped => "stopped",
+        }
+    }
+}
+
+/// What the supervisor last published about the guest.
+#[derive(Clone, Debug)]
+pub struct GuestStatus {
+    pub state: GuestState,
+    pub pid: Option<u32>,
+    pub started_at: Instant,
+    pub restarts: u32,
+    /// Restarts requested through `POST /restart`.
+    pub operator_restarts: u32,
+    /// The encoded attestation evidence of the current guest.
+    pub evidence: Option<Arc<[u8]>>,
+    /// How the previous guest exited, if one did.
+    pub last_exit: Option<String>,
+    /// QMP connection to the current guest's VMM, if it has one.
+    pub qmp: Option<QmpClient>,
+}
+
+/// Requests the admin API passes on to the supervisor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    Restart,
+    Shutdown,
+}
+
+/// Everything the admin API reports on or controls.
+pub struct AdminContext {
+    pub guest: watch::Receiver<GuestStatus>,
+    pub commands: mpsc::Sender<AdminCommand>,
+    pub console: ConsoleBuffer,
+    pub boot: BootTracker,
+    pub bridge_addresses: Vec<BridgeAddress>,
+    pub bridge_stats: Option<Arc<BridgeStats>>,
+}
+
+/// Parses an admin API address, which has to be a loopback address or a Unix
+/// socket.
+pub fn parse_address(s: &str) -> Result<BridgeAddress, String> {
+    let address: BridgeAddress = s.parse()?;
+    if !address.is_local() {
+        return Err(format!("{address} is not a loopback address"));
+    }
+    Ok(address)
+}
+
+/// Running admin API server. Stops serving when dropped.
+pub struct AdminServer {
+    task: JoinHandle<()>,
+}
+
+impl AdminServer {
+    pub async fn start(address: &BridgeAddress, context: AdminContext) -> Result<Self> {
+        let listener = Listener::bind(address).await.context("failed to start admin API")?;
+        let address = listener.local_address()?;
+        println!("admin API listening on {address}");
+
+        let context = Arc::new(context);
+        let started_at = Instant::now();
+        let task = tokio::spawn(async move {
+            let mut requests = JoinSet::new();
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            requests.spawn(serve_request(stream, context.clone(), started_at));
+                        }
+                        Err(err) => log::warn!("failed to accept admin connection: {}", err),
+                    },
+                    Some(_) = requests.join_next(), if !requests.is_empty() => {}
+                }
+            }
+        });
+        Ok(Self { task })
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// An HTTP response.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self { status: 200, content_type: "application/json", body },
+            Err(err) => Response::text(500, format!("{err}\n")),
+        }
+    }
+
+    fn text(status: u16, body: impl Into<String>) -> Self {
+        Self { status, content_type: "text/plain; charset=utf-8", body: body.into().into_bytes() }
+    }
+
+    async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        };
+        let head = format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        );
+        writer.write_all(head.as_bytes()).await?;
+        writer.write_all(&self.body).await?;
+        writer.flush().await
+    }
+}
+
+async fn serve_request(
+    stream: Box<dyn Connection>,
+    context: Arc<AdminContext>,
+    started_at: Instant,
+) {
+    let mut stream = BufReader::new(stream);
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) if request.has_origin => {
+            log::warn!(
+                "refused admin request {} {} from a web page",
+                request.method,
+                request.target
+            );
+            Response::text(403, "cross-origin requests are not allowed\n")
+        }
+        Ok(Ok(request)) if request.host.as_deref().is_some_and(|host| !is_loopback_host(host)) => {
+            log::warn!(
+                "refused admin request {} {} for another host",
+                request.method,
+                request.target
+            );
+            Response::text(403, "the Host header must name a loopback address\n")
+        }
+        Ok(Ok(request)) => {
+            log::debug!("admin request {} {}", request.method, request.target);
+            handle(&context, started_at, &request.method, &request.target).await
+        }
+        Ok(Err(err)) => Response::text(400, format!("{err:#}\n")),
+        Err(_) => Response::text(400, "request timed out\n"),
+    };
+    if let Err(err) = response.write(stream.get_mut()).await {
+        log::debug!("failed to write admin response: {}", err);
+    }
+}
+
+/// The parts of a request the admin API looks at.
+struct Request {
+    method: String,
+    target: String,
+    /// The `Host` header, if there was one.
+    host: Option<String>,
+    /// Whether there was an `Origin` header, which browsers send with
+    /// cross-origin and all `POST` requests.
+    has_origin: bool,
+}
+
+/// Returns whether a `Host` header value names a loopback address.
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']').map_or(bracketed, |(name, _)| name),
+        None => host.split_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Reads the request head and discards the body.
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Request> {
+    let mut head_length = 0;
+    let mut request_line = String::new();
+    let mut content_length = 0;
+    let mut host = None;
+    let mut has_origin = false;
+    loop {
+        let mut line = String::new();
+        let limit = (MAX_REQUEST_HEAD - head_length) as u64;
+        let read = (&mut *reader).take(limit).read_line(&mut line).await?;
+        head_length += read;
+        if read as u64 == limit && !line.ends_with('\n') {
+            bail!("request head too long");
+        }
+        let line = line.trim_end();
+        if request_line.is_empty() {
+            if line.is_empty() {
+                bail!("connection closed before the request line");
+            }
+            request_line = line.to_string();
+        } else if line.is_empty() {
+            break;
+        } else if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().context("invalid Content-Length")?;
+            } else if name.eq_ignore_ascii_case("host") {
+                host = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("origin") {
+                has_origin = true;
+            }
+        }
+    }
+    if content_length > MAX_REQUEST_BODY {
+        bail!("request body too long");
+    }
+    tokio::io::copy(&mut (&mut *reader).take(content_length), &mut tokio::io::sink()).await?;
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => {
+            Ok(Request { method: method.to_string(), target: target.to_string(), host, has_origin })
+        }
+        _ => bail!("malformed request line"),
+    }
+}
+
+async fn handle(
+    context: &AdminContext,
+    started_at: Instant,
+    method: &str,
+    target: &str,
+) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match (method, path) {
+        ("GET", "/status") => {
+            let vm = vm_status(context).await;
+            Response::json(&status(context, started_at, vm))
+        }
+        ("GET", "/evidence") => match &context.guest.borrow().evidence {
+            Some(evidence) => Response {
+                status: 200,
+                content_type: "application/octet-stream",
+                body: evidence.to_vec(),
+            },
+            None => Response::text(404, "no evidence received\n"),
+        },
+        ("GET", "/console") => {
+            let lines = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("lines="))
+                .map(str::parse)
+                .unwrap_or(Ok(DEFAULT_CONSOLE_LINES));
+            match lines {
+                Ok(lines) => Response::text(
+                    200,
+                    context
+                        .console
+                        .tail(lines)
+                        .iter()
+                        .map(|line| format!("{line}\n"))
+                        .collect::<String>(),
+                ),
+                Err(_) => Response::text(400, "invalid lines parameter\n"),
+            }
+        }
+        ("GET", "/stats") => Response::json(&stats(context)),
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics::render(context.bridge_stats.as_deref()).into_bytes(),
+        },
+        ("POST", "/pause" | "/resume" | "/reset") => {
+            let Some(qmp) = context.guest.borrow().qmp.clone() else {
+                return Response::text(503, "the guest has no QMP connection\n");
+            };
+            let result = match path {
+                "/pause" => qmp.pause().await,
+                "/resume" => qmp.resume().await,
+                _ => qmp.system_reset().await,
+            };
+            match result {
+                Ok(()) => {
+                    info!("admin API requested {}", &path[1..]);
+                    Response::text(200, "ok\n")
+                }
+                Err(err) => Response::text(500, format!("{err:#}\n")),
+            }
+        }
+        ("POST", "/restart") => send_command(context, AdminCommand::Restart),
+        ("POST", "/shutdown") => send_command(context, AdminCommand::Shutdown),
+        (
+            _,
+            "/status" | "/evidence" | "/console" | "/stats" | "/metrics" | "/pause" | "/resume"
+            | "/reset" | "/restart" | "/shutdown",
+        ) => Response::text(405, "method not allowed\n"),
+        _ => Response::text(404, "not found\n"),
+    }
+}
+
+/// Asks QEMU for the VM's run state, or returns `None` if there's no QMP
+/// connection or QEMU doesn't answer.
+async fn vm_status(context: &AdminContext) -> Option<VmStatus> {
+    let qmp = context.guest.borrow().qmp.clone()?;
+    match tokio::time::timeout(QMP_STATUS_TIMEOUT, qmp.query_status()).await {
+        Ok(Ok(status)) => Some(status),
+        Ok(Err(err)) => {
+            log::warn!("couldn't query VM status: {:?}", err);
+            None
+        }
+        Err(_) => {
+            log::warn!("timed out querying VM status");
+            None
+        }
+    }
+}
+
+/// Body of `GET /status`.
+#[derive(Serialize)]
+struct Status {
+    state: &'static str,
+    vm: Option<VmStatus>,
+    boot: BootStatus,
+    pid: Option<u32>,
+    uptime_secs: f64,
+    guest_uptime_secs: f64,
+    restarts: u32,
+    operator_restarts: u32,
+    last_exit: Option<String>,
+    bridge: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BootStatus {
+    phase: Option<&'static str>,
+    transitions: Vec<BootTransitionStatus>,
+}
+
+#[derive(Serialize)]
+struct BootTransitionStatus {
+    phase: &'static str,
+    at: String,
+}
+
+fn status(context: &AdminContext, started_at: Instant, vm: Option<VmStatus>) -> Status {
+    let guest = context.guest.borrow();
+    Status {
+        state: guest.state.as_str(),
+        vm,
+        boot: BootStatus {
+            phase: context.boot.phase().map(BootPhase::as_str),
+            transitions: context
+                .boot
+                .transitions()
+                .iter()
+                .map(|transition| BootTransitionStatus {
+                    phase: transition.phase.as_str(),
+                    at: format_timestamp(transition.at),
+                })
+                .collect(),
+        },
+        pid: guest.pid,
+        uptime_secs: started_at.elapsed().as_secs_f64(),
+        guest_uptime_secs: guest.started_at.elapsed().as_secs_f64(),
+        restarts: guest.restarts,
+        operator_restarts: guest.operator_restarts,
+        last_exit: guest.last_exit.clone(),
+        bridge: context.bridge_addresses.iter().map(ToString::to_string).collect(),
+    }
+}
+
+/// Body of `GET /stats`.
+#[derive(Serialize)]
+struct Stats {
+    bridge: Option<BridgeCounters>,
+}
+
+#[derive(Serialize)]
+struct BridgeCounters {
+    connections_accepted: u64,
+    connections_rejected: u64,
+    accept_errors: u64,
+    connections_active: u64,
+    requests: u64,
+    request_errors: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+fn stats(context: &AdminContext) -> Stats {
+    Stats {
+        bridge: context.bridge_stats.as_deref().map(|stats| BridgeCounters {
+            connections_accepted: stats.connections_accepted.load(Ordering::Relaxed),
+            connections_rejected: stats.connections_rejected.load(Ordering::Relaxed),
+            accept_errors: stats.accept_errors.load(Ordering::Relaxed),
+            connections_active: stats.connections_active.load(Ordering::Relaxed),
+            requests: stats.requests.load(Ordering::Relaxed),
+            request_errors: stats.request_errors.load(Ordering::Relaxed),
+            bytes_received: stats.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+        }),
+    }
+}
+
+fn send_command(context: &AdminContext, command: AdminCommand) -> Response {
+    match context.commands.try_send(command) {
+        Ok(()) => {
+            info!("admin API requested {:?}", command);
+            Response::text(202, "accepted\n")
+        }
+        Err(_) => Response::text(503, "another request is being processed\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_request_picks_out_host_and_origin() {
+        let mut head: &[u8] = b"POST /restart HTTP/1.1\r\nHost: localhost:8080\r\n\
+                                Origin: http://example.com\r\nContent-Length: 2\r\n\r\n{}";
+        let request = read_request(&mut head).await.unwrap();
+        assert_eq!((request.method.as_str(), request.target.as_str()), ("POST", "/restart"));
+        assert_eq!(request.host.as_deref(), Some("localhost:8080"));
+        assert!(request.has_origin);
+        assert!(head.is_empty(), "the body should have been consumed");
+
+        let mut head: &[u8] = b"GET /status HTTP/1.0\r\n\r\n";
+        let request = read_request(&mut head).await.unwrap();
+        assert_eq!(request.host, None);
+        assert!(!request.has_origin);
+    }
+
+    #[tokio::test]
+    async fn read_request_stops_at_the_head_limit() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let writer = tokio::spawn(async move {
+            client.write_all(b"GET /").await.unwrap();
+            // Keeps sending without ever ending the line.
+            while client.write_all(&[b'a'; 1024]).await.is_ok() {}
+        });
+
+        let Err(err) = read_request(&mut BufReader::new(server)).await else {
+            panic!("the request should have been refused");
+        };
+        assert!(format!("{err:#}").contains("request head too long"), "{err:#}");
+        writer.await.unwrap();
+    }
+
+    #[test]
+    fn loopback_hosts() {
+        for host in
+            ["localhost", "LOCALHOST:80", "127.0.0.1", "127.0.0.1:8080", "[::1]", "[::1]:80"]
+        {
+            assert!(is_loopback_host(host), "{host}");
+        }
+        for host in ["example.com", "localhost.example.com", "10.0.0.1:80", "[::2]:80", ""] {
+            assert!(!is_loopback_host(host), "{host}");
+        }
+    }
+}
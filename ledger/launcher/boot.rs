@@ -0,0 +1,264 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/boot.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! The phases a guest goes through while it is being started.
+//!
+//! ```text
+//! Spawning -> LoadingApp -> AwaitingEvidence -> Ready
+//!     \            \               \
+//!      `------------`---------------`--> Failed
+//! ```
+//!
+//! `LoadingApp` is skipped when there is no application to send, and
+//! `AwaitingEvidence` when evidence exchange is disabled; no other transitions
+//! are allowed, and `Ready` and `Failed` are final. Every phase that
+//! waits on the guest has to be done within its own timeout of being entered,
+//! see [`BootTimeouts`] and [`BootTracker::deadline`].
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::{metrics::METRICS, Params};
+
+/// Phase of starting a guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootPhase {
+    /// Starting the VMM or native process and connecting to the guest.
+    Spawning,
+    /// Sending the initial data with the application to the guest.
+    LoadingApp,
+    /// Waiting for the guest to send its attestation evidence.
+    AwaitingEvidence,
+    /// The guest is running and connected.
+    Ready,
+    /// Starting the guest failed.
+    Failed,
+}
+
+impl BootPhase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BootPhase::Spawning => "spawning",
+            BootPhase::LoadingApp => "loading_app",
+            BootPhase::AwaitingEvidence => "awaiting_evidence",
+            BootPhase::Ready => "ready",
+            BootPhase::Failed => "failed",
+        }
+    }
+
+    /// Whether a boot in this phase may move on to `next`.
+    pub fn can_enter(self, next: BootPhase) -> bool {
+        use BootPhase::*;
+        matches!(
+            (self, next),
+            (Spawning, LoadingApp | AwaitingEvidence | Ready | Failed)
+                | (LoadingApp, AwaitingEvidence | Ready | Failed)
+                | (AwaitingEvidence, Ready | Failed)
+        )
+    }
+
+    /// Whether the boot is over.
+    pub fn is_final(self) -> bool {
+        matches!(self, BootPhase::Ready | BootPhase::Failed)
+    }
+}
+
+impl fmt::Display for BootPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Entering a boot phase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootTransition {
+    pub phase: BootPhase,
+    pub at: SystemTime,
+    elapsed_at: Instant,
+}
+
+/// How long each phase may take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootTimeouts {
+    /// For the guest to boot. With vsock that's until it accepts the
+    /// connection. Over a socketpair the guest only shows that it has booted by
+    /// reading the initial data, so what's left of this is added to
+    /// `loading_app`.
+    pub spawning: Duration,
+    /// For sending the initial data.
+    pub loading_app: Duration,
+    /// For the guest to send its evidence.
+    pub awaiting_evidence: Duration,
+}
+
+impl BootTimeouts {
+    pub fn from_params(params: &Params) -> Self {
+        Self {
+            spawning: Duration::from_millis(params.spawn_timeout_ms),
+            loading_app: Duration::from_millis(params.load_app_timeout_ms),
+            awaiting_evidence: Duration::from_millis(params.evidence_timeout_ms),
+        }
+    }
+}
+
+/// Records the boot phases of the most recently started guest. Cheap to clone;
+/// all clones share the same record.
+#[derive(Clone, Debug, Default)]
+pub struct BootTracker {
+    transitions: Arc<Mutex<Vec<BootTransition>>>,
+}
+
+impl BootTracker {
+    /// Starts recording a new boot, forgetting the previous one.
+    pub fn begin(&self) {
+        self.transitions.lock().unwrap().clear();
+        self.enter(BootPhase::Spawning);
+    }
+
+    /// Moves on to the given phase. A transition the state machine doesn't
+    /// allow is a bug; it's ignored in release builds.
+    pub fn enter(&self, phase: BootPhase) {
+        let now = Instant::now();
+        let mut transitions = self.transitions.lock().unwrap();
+        let previous = transitions.last().map(|transition| transition.phase);
+        let allowed = match previous {
+            Some(previous) => previous.can_enter(phase),
+            None => phase == BootPhase::Spawning,
+        };
+        debug_assert!(allowed, "invalid boot transition {:?} -> {}", previous, phase);
+        if !allowed {
+            log::error!("ignoring invalid boot transition {:?} -> {}", previous, phase);
+            return;
+        }
+        match transitions.last() {
+            Some(previous) => {
+                let duration = now - previous.elapsed_at;
+                METRICS.boot_phase_duration.observe_duration(previous.phase.as_str(), duration);
+                log::info!("boot phase {} -> {} after {:?}", previous.phase, phase, duration);
+            }
+            None => log::info!("boot phase {}", phase),
+        }
+        transitions.push(BootTransition { phase, at: SystemTime::now(), elapsed_at: now });
+    }
+
+    /// Marks the boot as failed, unless it already completed or failed.
+    pub fn fail(&self, err: &anyhow::Error) {
+        if let Some(phase) = self.phase().filter(|phase| !phase.is_final()) {
+            log::error!("boot failed during {}: {:?}", phase, err);
+            self.enter(BootPhase::Failed);
+        }
+    }
+
+    /// Returns when the current phase has to be done by if it may take
+    /// `timeout`.
+    pub fn deadline(&self, timeout: Duration) -> Instant {
+        let entered =
+            self.transitions.lock().unwrap().last().map(|transition| transition.elapsed_at);
+        entered.unwrap_or_else(Instant::now) + timeout
+    }
+
+    /// Returns the current phase, if a boot has begun.
+    pub fn phase(&self) -> Option<BootPhase> {
+        self.transitions.lock().unwrap().last().map(|transition| transition.phase)
+    }
+
+    /// Returns the phases of the current boot so far, in order.
+    pub fn transitions(&self) -> Vec<BootTransition> {
+        self.transitions.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHASES: [BootPhase; 5] = [
+        BootPhase::Spawning,
+        BootPhase::LoadingApp,
+        BootPhase::AwaitingEvidence,
+        BootPhase::Ready,
+        BootPhase::Failed,
+    ];
+
+    #[test]
+    fn phases_only_move_forward() {
+        for (i, &from) in PHASES.iter().enumerate() {
+            for (j, &to) in PHASES.iter().enumerate() {
+                assert_eq!(from.can_enter(to), !from.is_final() && j > i, "{from} -> {to}");
+            }
+        }
+    }
+
+    #[test]
+    fn boot_can_skip_phases() {
+        let boot = BootTracker::default();
+        boot.begin();
+        boot.enter(BootPhase::Ready);
+        let phases: Vec<_> = boot.transitions().iter().map(|transition| transition.phase).collect();
+        assert_eq!(phases, [BootPhase::Spawning, BootPhase::Ready]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "invalid boot transition")]
+    fn boot_cant_go_back() {
+        let boot = BootTracker::default();
+        boot.begin();
+        boot.enter(BootPhase::Ready);
+        boot.enter(BootPhase::Spawning);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "invalid boot transition")]
+    fn boot_has_to_begin_with_spawning() {
+        BootTracker::default().enter(BootPhase::LoadingApp);
+    }
+
+    #[test]
+    fn deadline_counts_from_entering_the_phase() {
+        let boot = BootTracker::default();
+        boot.begin();
+        std::thread::sleep(Duration::from_millis(10));
+        boot.enter(BootPhase::LoadingApp);
+        let entered = boot.transitions().last().unwrap().elapsed_at;
+        let timeout = Duration::from_secs(5);
+        assert_eq!(boot.deadline(timeout), entered + timeout);
+    }
+
+    #[test]
+    fn fail_is_a_no_op_once_ready() {
+        let boot = BootTracker::default();
+        boot.begin();
+        boot.enter(BootPhase::Ready);
+        boot.fail(&anyhow::anyhow!("connection lost"));
+        assert_eq!(boot.phase(), Some(BootPhase::Ready));
+        assert_eq!(boot.transitions().len(), 2);
+    }
+
+    #[test]
+    fn fail_ends_the_boot() {
+        let boot = BootTracker::default();
+        boot.begin();
+        boot.fail(&anyhow::anyhow!("couldn't start the VMM"));
+        assert_eq!(boot.phase(), Some(BootPhase::Failed));
+        boot.fail(&anyhow::anyhow!("again"));
+        assert_eq!(boot.transitions().len(), 2);
+    }
+}
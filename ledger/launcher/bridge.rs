@@ -0,0 +1,687 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/bridge.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Bridge that forwards client messages to the guest and writes the
+//! guest's responses back to the client.
+//!
+//! # Wire protocol
+//!
+//! The bridge listens on one or more TCP addresses or Unix sockets (see
+//! [`BridgeAddress`]). A connection carries any number of request/response
+//! pairs. Every message in either direction is a single frame:
+//!
+//! ```text
+//! +----------------------+------------------------+
+//! | length: u32 (LE)     | body: `length` bytes   |
+//! +----------------------+------------------------+
+//! ```
+//!
+//! The client sends a request frame and waits for the response frame before
+//! sending the next request; responses are returned in request order. The body
+//! of a request frame is passed to the guest as-is, and the body of the
+//! response frame is exactly what the guest returned. Request frames larger
+//! than [`MAX_FRAME_SIZE`] are rejected and the connection is closed. At most
+//! [`MAX_CONNECTIONS`] connections are served at once. Closing the
+//! connection between frames ends the session; there is no need to half-close
+//! the socket to mark the end of a message.
+//!
+//! The bridge only forwards to the guest. The attestation evidence the guest
+//! sent during startup is served by the admin API instead.
+
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::info;
+use micro_rpc::AsyncTransport;
+use prost::Message;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
+    sync::{watch, Semaphore},
+    task::JoinSet,
+};
+
+use super::metrics::{bounded_label, HistogramFamily, LATENCY_BUCKETS};
+use crate::channel::ConnectorHandle;
+
+/// Address the bridge listens on unless configured otherwise. Only local
+/// clients can reach it.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:46787";
+
+/// Address the bridge can listen on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BridgeAddress {
+    /// TCP socket address, e.g. `127.0.0.1:46787` or `[::1]:46787`. Port 0 lets
+    /// the OS pick a free port.
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket, e.g. `unix:/run/ledger/bridge.sock`.
+    Unix(PathBuf),
+}
+
+impl BridgeAddress {
+    /// Returns whether only clients on this host can connect to the address.
+    pub fn is_local(&self) -> bool {
+        match self {
+            Self::Tcp(addr) => addr.ip().is_loopback(),
+            Self::Unix(_) => true,
+        }
+    }
+}
+
+impl FromStr for BridgeAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(String::from("unix socket path must not be empty"));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        addr.parse().map(Self::Tcp).map_err(|err| format!("invalid TCP address {addr:?}: {err}"))
+    }
+}
+
+impl fmt::Display for BridgeAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Largest frame body accepted from or sent to a client. Ledger requests and
+/// responses are a few KiB at most.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Largest number of client connections served at once, over all listeners.
+/// Connections beyond that are closed right after they are accepted.
+pub const MAX_CONNECTIONS: usize = 64;
+
+/// Delay before accepting again after the first of consecutive accept errors;
+/// doubles with every further error up to [`MAX_ACCEPT_RETRY_DELAY`].
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Upper bound for the delay before accepting again after an error.
+const MAX_ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Size of the length prefix at the start of every frame.
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// Reads a single frame from the reader.
+///
+/// Returns `None` if the peer closed the connection cleanly before the start of
+/// a new frame.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    match read_header(reader).await? {
+        Some(length) => read_body(reader, length).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Reads a frame header and returns the length it announces, or `None` if the
+/// peer closed the connection cleanly.
+async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < FRAME_HEADER_SIZE {
+        match reader.read(&mut header[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a frame header",
+                ))
+            }
+            n => filled += n,
+        }
+    }
+
+    Ok(Some(u32::from_le_bytes(header)))
+}
+
+/// Reads a frame body of the given length. The body is read as it arrives
+/// rather than allocated up front, so a peer that announces a large frame and
+/// then stalls doesn't get to hold on to the memory.
+async fn read_body<R: AsyncRead + Unpin>(reader: &mut R, length: u32) -> io::Result<Vec<u8>> {
+    let length = length as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {length} bytes exceeds the maximum of {MAX_FRAME_SIZE} bytes"),
+        ));
+    }
+
+    let mut body = Vec::new();
+    reader.take(length as u64).read_to_end(&mut body).await?;
+    if body.len() < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("connection closed after {} of {length} frame bytes", body.len()),
+        ));
+    }
+    Ok(body)
+}
+
+/// Writes the body to the writer as a single frame.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, body: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(body.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes doesn't fit in a frame header", body.len()),
+        )
+    })?;
+    writer.write_all(&length.to_le_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.flush().await
+}
+
+/// A client connection accepted by a [`Listener`].
+pub(super) trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// A bound TCP or Unix socket listener. The socket file of a Unix listener is
+/// removed when it is dropped.
+pub(super) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub(super) async fn bind(address: &BridgeAddress) -> Result<Self> {
+        match address {
+            BridgeAddress::Tcp(addr) => TcpListener::bind(addr)
+                .await
+                .map(Self::Tcp)
+                .with_context(|| format!("failed to bind to {address}")),
+            BridgeAddress::Unix(path) => {
+                remove_stale_socket(path)?;
+                UnixListener::bind(path)
+                    .map(|listener| Self::Unix(listener, path.clone()))
+                    .with_context(|| format!("failed to bind to {address}"))
+            }
+        }
+    }
+
+    /// Returns the address the listener is actually bound to, which differs from
+    /// the requested one if port 0 was used.
+    pub(super) fn local_address(&self) -> Result<BridgeAddress> {
+        match self {
+            Self::Tcp(listener) => Ok(BridgeAddress::Tcp(listener.local_addr()?)),
+            Self::Unix(_, path) => Ok(BridgeAddress::Unix(path.clone())),
+        }
+    }
+
+    /// Accepts a new connection, returning it along with a description of the
+    /// peer for logging.
+    pub(super) async fn accept(&self) -> io::Result<(Box<dyn Connection>, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            Self::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), format!("unix:{}", path.display())))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Cleans up a socket left behind by a previous run, but never removes anything
+/// that isn't a socket or a socket another process is still listening on.
+pub(super) fn remove_stale_socket(path: &Path) -> Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type()) {
+        return Ok(());
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => anyhow::bail!("{} is in use by another process", path.display()),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)
+            .with_context(|| format!("failed to remove stale socket {}", path.display())),
+        Err(err) => Err(err).with_context(|| format!("failed to check socket {}", path.display())),
+    }
+}
+
+/// Counters describing the bridge's traffic since it started.
+pub struct BridgeStats {
+    pub connections_accepted: AtomicU64,
+    /// Connections closed because too many were open.
+    pub connections_rejected: AtomicU64,
+    /// Errors accepting connections, e.g. because the launcher ran out of file
+    /// descriptors.
+    pub accept_errors: AtomicU64,
+    pub connections_active: AtomicU64,
+    /// Requests forwarded to the guest, including failed ones.
+    pub requests: AtomicU64,
+    /// Requests the guest couldn't be invoked for.
+    pub request_errors: AtomicU64,
+    /// Request bytes received from clients, excluding frame headers.
+    pub bytes_received: AtomicU64,
+    /// Response bytes sent to clients, excluding frame headers.
+    pub bytes_sent: AtomicU64,
+    /// Time the guest took to respond, by micro RPC method id.
+    pub request_duration: HistogramFamily,
+}
+
+impl Default for BridgeStats {
+    fn default() -> Self {
+        Self {
+            connections_accepted: Default::default(),
+            connections_rejected: Default::default(),
+            accept_errors: Default::default(),
+            connections_active: Default::default(),
+            requests: Default::default(),
+            request_errors: Default::default(),
+            bytes_received: Default::default(),
+            bytes_sent: Default::default(),
+            request_duration: HistogramFamily::new(LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl BridgeStats {
+    fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+/// Keeps the active connection count up to date for as long as a connection
+/// is being served.
+struct ActiveConnection(Arc<BridgeStats>);
+
+impl ActiveConnection {
+    fn new(stats: Arc<BridgeStats>) -> Self {
+        BridgeStats::add(&stats.connections_accepted, 1);
+        BridgeStats::add(&stats.connections_active, 1);
+        Self(stats)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Running bridge that forwards client messages to the guest through the
+/// connector.
+///
+/// All requests go through the same [`ConnectorHandle`] as any other host
+/// traffic, so the connector is the only writer on the channel and is
+/// responsible for assigning invocation ids and matching up responses.
+///
+/// The bridge outlives individual guests: it stays bound while a guest is being
+/// restarted, and requests that arrive while no guest is connected wait until
+/// [`Bridge::connect_guest`] is called with the next one.
+///
+/// Every listener runs as its own tokio task and every accepted connection is
+/// served concurrently on a task of its own. Dropping the bridge aborts all of
+/// them; use [`Bridge::shutdown`] to stop it cleanly instead.
+pub struct Bridge {
+    addresses: Vec<BridgeAddress>,
+    guest: watch::Sender<Option<ConnectorHandle>>,
+    shutdown: watch::Sender<bool>,
+    stats: Arc<BridgeStats>,
+    tasks: JoinSet<()>,
+}
+
+impl Bridge {
+    /// Binds the bridge to the given addresses and starts serving connections.
+    ///
+    /// The bound addresses are printed to stdout and, if `address_file` is given,
+    /// written to that file one per line, so that scripts using port 0 can find
+    /// out which port was picked.
+    ///
+    /// No guest is connected initially; see [`Bridge::connect_guest`].
+    pub async fn start(addresses: &[BridgeAddress], address_file: Option<&Path>) -> Result<Self> {
+        let mut listeners = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            listeners.push(Listener::bind(address).await?);
+        }
+        let bound = listeners.iter().map(Listener::local_address).collect::<Result<Vec<_>>>()?;
+
+        for address in &bound {
+            println!("bridge listening on {address}");
+        }
+        if let Some(address_file) = address_file {
+            let contents: String = bound.iter().map(|address| format!("{address}\n")).collect();
+            fs::write(address_file, contents).with_context(|| {
+                format!("failed to write bridge addresses to {}", address_file.display())
+            })?;
+        }
+
+        let (guest, _) = watch::channel(None);
+        let (shutdown, _) = watch::channel(false);
+        let stats = Arc::new(BridgeStats::default());
+        // Shared by all listeners, so that the limit holds across them.
+        let connection_slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        let mut tasks = JoinSet::new();
+        for (listener, address) in listeners.into_iter().zip(&bound) {
+            info!("Listening on {} for messages to forward to the guest.", address);
+            tasks.spawn(accept_loop(
+                listener,
+                guest.subscribe(),
+                shutdown.subscribe(),
+                stats.clone(),
+                connection_slots.clone(),
+            ));
+        }
+
+        Ok(Self { addresses: bound, guest, shutdown, stats, tasks })
+    }
+
+    /// Starts forwarding requests to the guest behind the connector.
+    pub fn connect_guest(&self, connector_handle: ConnectorHandle) {
+        self.guest.send_replace(Some(connector_handle));
+    }
+
+    /// Stops forwarding requests to the current guest, e.g. because it is being
+    /// restarted. New requests are held until the next guest is connected.
+    pub fn disconnect_guest(&self) {
+        self.guest.send_replace(None);
+    }
+
+    /// Returns the addresses the bridge is bound to.
+    pub fn addresses(&self) -> &[BridgeAddress] {
+        &self.addresses
+    }
+
+    /// Returns the traffic counters, which stay valid after the bridge is shut
+    /// down.
+    pub fn stats(&self) -> Arc<BridgeStats> {
+        self.stats.clone()
+    }
+
+    /// Stops accepting new connections, closes idle connections and waits up to
+    /// `deadline` for requests that are already being processed to complete.
+    /// Connections still busy after that are dropped.
+    pub async fn shutdown(mut self, deadline: Duration) {
+        info!("shutting down bridge");
+        let _ = self.shutdown.send(true);
+        let drained = tokio::time::timeout(deadline, async {
+            while self.tasks.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            log::warn!("bridge requests did not complete within {:?}, dropping them", deadline);
+            self.tasks.shutdown().await;
+        }
+    }
+}
+
+/// Accepts connections until shutdown is requested, serving each of them
+/// concurrently, as clients may keep their connection open for as long as they
+/// like.
+///
+/// Every connection holds one of the `connection_slots` the listeners share
+/// while it's served; connections accepted when none is left are closed. After
+/// an accept error the loop backs off, so that a persistent error like running
+/// out of file descriptors doesn't keep it spinning.
+async fn accept_loop(
+    listener: Listener,
+    guest: watch::Receiver<Option<ConnectorHandle>>,
+    mut shutdown: watch::Receiver<bool>,
+    stats: Arc<BridgeStats>,
+    connection_slots: Arc<Semaphore>,
+) {
+    let mut connections = JoinSet::new();
+    let mut retry_delay = ACCEPT_RETRY_DELAY;
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    retry_delay = ACCEPT_RETRY_DELAY;
+                    let Ok(slot) = connection_slots.clone().try_acquire_owned() else {
+                        BridgeStats::add(&stats.connections_rejected, 1);
+                        log::warn!(
+                            "Closing bridge connection from {}: already serving {} connections.",
+                            peer,
+                            MAX_CONNECTIONS
+                        );
+                        continue;
+                    };
+                    info!("Accepted bridge connection from {}.", peer);
+                    let guest = guest.clone();
+                    let shutdown = shutdown.clone();
+                    let stats = stats.clone();
+                    connections.spawn(async move {
+                        let active = ActiveConnection::new(stats);
+                        if let Err(e) = serve_connection(stream, guest, shutdown, &active.0).await {
+                            log::error!("Bridge connection from {} failed: {:?}", peer, e);
+                        }
+                        info!("Bridge connection from {} closed.", peer);
+                        drop(slot);
+                    });
+                }
+                Err(e) => {
+                    BridgeStats::add(&stats.accept_errors, 1);
+                    log::error!(
+                        "Failed to accept incoming bridge connection, retrying in {:?}: {:?}",
+                        retry_delay,
+                        e
+                    );
+                    tokio::select! {
+                        _ = shutdown.changed() => break,
+                        _ = tokio::time::sleep(retry_delay) => {}
+                    }
+                    retry_delay = (retry_delay * 2).min(MAX_ACCEPT_RETRY_DELAY);
+                }
+            },
+            // Reap connections that have finished so the set doesn't grow forever.
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+}
+
+/// Forwards request frames from the client to the guest and writes the guest's
+/// responses back as frames until the client closes the connection or shutdown
+/// is requested.
+async fn serve_connection(
+    mut stream: Box<dyn Connection>,
+    mut guest: watch::Receiver<Option<ConnectorHandle>>,
+    mut shutdown: watch::Receiver<bool>,
+    stats: &BridgeStats,
+) -> Result<()> {
+    loop {
+        // A client that stalls in the middle of a frame mustn't hold up shutdown.
+        let request = tokio::select! {
+            _ = shutdown.changed() => break,
+            request = read_frame(&mut stream) => {
+                match request.context("failed to read request frame")? {
+                    Some(request) => request,
+                    None => break,
+                }
+            }
+        };
+
+        let mut connector_handle = tokio::select! {
+            _ = shutdown.changed() => break,
+            connector_handle = current_guest(&mut guest) => connector_handle?,
+        };
+        BridgeStats::add(&stats.requests, 1);
+        BridgeStats::add(&stats.bytes_received, request.len() as u64);
+        log::debug!("Read {} byte request frame, forwarding to guest.", request.len());
+        // Also print the raw bytes in hexadecimal for detailed debugging.
+        log::trace!("Request content (hex): {:x?}", request);
+
+        let started = Instant::now();
+        let result = connector_handle.invoke(&request).await;
+        stats.request_duration.observe_duration(&method_label(&request), started.elapsed());
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                BridgeStats::add(&stats.request_errors, 1);
+                return Err(err).context("failed to invoke guest");
+            }
+        };
+        log::debug!("Received response from guest ({} bytes), writing it back.", response.len());
+
+        write_frame(&mut stream, &response).await.context("failed to write response frame")?;
+        BridgeStats::add(&stats.bytes_sent, response.len() as u64);
+    }
+    Ok(())
+}
+
+/// The leading field of a micro RPC request, which identifies the method.
+#[derive(Clone, PartialEq, Message)]
+struct RequestHeader {
+    #[prost(uint32, tag = "1")]
+    method_id: u32,
+}
+
+/// Method ids of the ledger's micro RPC service: `CreateKey`, `DeleteKey`,
+/// `AuthorizeAccess` and `RevokeAccess`.
+const LEDGER_METHOD_IDS: &[&str] = &["1", "2", "3", "4"];
+
+/// Labels a request with its micro RPC method id, for metrics. Requests for
+/// other methods share one label, so clients can't create new series.
+fn method_label(request: &[u8]) -> String {
+    match RequestHeader::decode(request) {
+        Ok(header) => bounded_label(&header.method_id.to_string(), LEDGER_METHOD_IDS).to_string(),
+        Err(_) => String::from("unknown"),
+    }
+}
+
+/// Returns the guest to forward to, waiting for one to be connected if the
+/// current guest is being restarted.
+async fn current_guest(
+    guest: &mut watch::Receiver<Option<ConnectorHandle>>,
+) -> Result<ConnectorHandle> {
+    let guest = guest.wait_for(Option::is_some).await.context("bridge has been shut down")?;
+    Ok(guest.clone().expect("guest must be connected"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::duplex, net::TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn only_loopback_and_unix_addresses_are_local() {
+        for address in ["127.0.0.1:46787", "[::1]:46787", "unix:/tmp/bridge.sock"] {
+            assert!(address.parse::<BridgeAddress>().unwrap().is_local(), "{address}");
+        }
+        for address in ["0.0.0.0:46787", "[::]:46787", "192.168.1.2:46787"] {
+            assert!(!address.parse::<BridgeAddress>().unwrap().is_local(), "{address}");
+        }
+    }
+
+    #[tokio::test]
+    async fn read_frame_reads_body_as_it_arrives() {
+        let (mut client, mut server) = duplex(64);
+        let reading = tokio::spawn(async move { read_frame(&mut server).await });
+        client.write_all(&10u32.to_le_bytes()).await.unwrap();
+        client.write_all(b"01234").await.unwrap();
+        tokio::task::yield_now().await;
+        client.write_all(b"56789").await.unwrap();
+
+        assert_eq!(reading.await.unwrap().unwrap().unwrap(), b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn read_frame_rejects_truncated_and_oversized_frames() {
+        let (mut client, mut server) = duplex(64);
+        client.write_all(&10u32.to_le_bytes()).await.unwrap();
+        client.write_all(b"01234").await.unwrap();
+        drop(client);
+        let err = read_frame(&mut server).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let (mut client, mut server) = duplex(64);
+        client.write_all(&(MAX_FRAME_SIZE as u32 + 1).to_le_bytes()).await.unwrap();
+        let err = read_frame(&mut server).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn bridge_closes_connections_beyond_the_limit() {
+        let listen = ["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+        let bridge = Bridge::start(&listen, None).await.unwrap();
+        let addresses: Vec<SocketAddr> = bridge
+            .addresses()
+            .iter()
+            .map(|address| match address {
+                BridgeAddress::Tcp(address) => *address,
+                BridgeAddress::Unix(_) => unreachable!(),
+            })
+            .collect();
+        let stats = bridge.stats();
+
+        // The limit is shared by both listeners.
+        let mut open = Vec::new();
+        for index in 0..MAX_CONNECTIONS {
+            open.push(TcpStream::connect(addresses[index % 2]).await.unwrap());
+        }
+        while stats.connections_active.load(Ordering::Relaxed) < MAX_CONNECTIONS as u64 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        for address in &addresses {
+            let mut extra = TcpStream::connect(address).await.unwrap();
+            assert_eq!(extra.read(&mut [0; 4]).await.unwrap(), 0);
+        }
+        assert_eq!(stats.connections_rejected.load(Ordering::Relaxed), 2);
+
+        drop(open);
+        bridge.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_doesnt_wait_for_a_stalled_request_body() {
+        let bridge = Bridge::start(&["127.0.0.1:0".parse().unwrap()], None).await.unwrap();
+        let BridgeAddress::Tcp(address) = bridge.addresses()[0].clone() else { unreachable!() };
+        let stats = bridge.stats();
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&10u32.to_le_bytes()).await.unwrap();
+        client.write_all(b"01234").await.unwrap();
+        while stats.connections_active.load(Ordering::Relaxed) < 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), bridge.shutdown(Duration::from_secs(60)))
+            .await
+            .expect("shutdown should not wait for the rest of the frame");
+    }
+}
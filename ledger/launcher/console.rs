@@ -0,0 +1,752 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/console.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Captures the guest console.
+//!
+//! Every line the guest writes is timestamped and written to stdout, to an
+//! optional size-rotated log file and to an in-memory ring buffer that can be
+//! queried while the launcher runs. The console outlives individual guests, so
+//! the history is kept across restarts.
+//!
+//! Lines matching a known panic or fatal error signature are also published as
+//! [`ConsoleEvent`]s, so the launcher can react to a guest that is stuck after
+//! a failure instead of waiting for it to exit.
+//!
+//! The console can also be exposed on a Unix socket with [`ConsoleSocket`].
+//! Clients attached to it see the raw guest output and whatever they write is
+//! forwarded to the guest's serial port.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::unix::OwnedWriteHalf,
+    sync::broadcast,
+    task::{JoinHandle, JoinSet},
+};
+
+use super::{bridge::remove_stale_socket, metrics::METRICS, Params};
+
+/// A single line of guest console output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsoleLine {
+    pub timestamp: SystemTime,
+    /// The line without its line ending. Bytes that aren't valid UTF-8 are
+    /// replaced with U+FFFD.
+    pub text: String,
+}
+
+impl std::fmt::Display for ConsoleLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", format_timestamp(self.timestamp), self.text)
+    }
+}
+
+/// Longest line recorded as one entry. Output without line breaks is split.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Lines waiting to be written to stdout or the log file. Lines beyond this
+/// are dropped rather than holding up the guest.
+const LOG_QUEUE_LINES: usize = 4096;
+
+/// Signatures of guest failures, matched against every console line.
+///
+/// The restricted kernel and Rust applications both report panics with the
+/// standard `panicked at` message; the last one comes from `start_blocking_server`
+/// giving up on the channel.
+const DEFAULT_FATAL_PATTERNS: &[(ConsoleEventKind, &str)] = &[
+    (ConsoleEventKind::Panic, "panicked at"),
+    (ConsoleEventKind::Panic, "kernel panic"),
+    (ConsoleEventKind::Fatal, "couldn't receive message"),
+];
+
+/// Kind of failure recognised in the console output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleEventKind {
+    Panic,
+    Fatal,
+}
+
+impl std::fmt::Display for ConsoleEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConsoleEventKind::Panic => write!(f, "panic"),
+            ConsoleEventKind::Fatal => write!(f, "fatal error"),
+        }
+    }
+}
+
+/// A guest failure recognised in the console output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsoleEvent {
+    pub kind: ConsoleEventKind,
+    /// The pattern that matched.
+    pub pattern: String,
+    /// The console line the pattern was found in.
+    pub line: ConsoleLine,
+}
+
+impl std::fmt::Display for ConsoleEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "guest {}: {}", self.kind, self.line.text)
+    }
+}
+
+/// What the launcher does when the guest reports a panic or fatal error.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum FatalAction {
+    /// Only log the event.
+    #[default]
+    Log,
+    /// Kill the guest and exit with an error, without restarting it.
+    Kill,
+}
+
+/// Settings for console capture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsoleConfig {
+    /// File to append console output to.
+    pub log_file: Option<PathBuf>,
+    /// Size at which the log file is rotated.
+    pub max_file_bytes: u64,
+    /// Number of rotated log files to keep next to the current one.
+    pub max_files: usize,
+    /// Number of lines kept in memory.
+    pub buffer_lines: usize,
+    /// Additional patterns that mark a line as a fatal error.
+    pub fatal_patterns: Vec<String>,
+}
+
+impl ConsoleConfig {
+    pub fn from_params(params: &Params) -> Self {
+        Self {
+            log_file: params.console_log.clone(),
+            max_file_bytes: params.console_log_max_bytes,
+            max_files: params.console_log_files,
+            buffer_lines: params.console_buffer_lines,
+            fatal_patterns: params.fatal_pattern.clone(),
+        }
+    }
+}
+
+/// In-memory ring buffer holding the most recent console lines.
+#[derive(Clone, Debug)]
+pub struct ConsoleBuffer {
+    lines: Arc<Mutex<VecDeque<ConsoleLine>>>,
+    capacity: usize,
+}
+
+impl ConsoleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self { lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))), capacity }
+    }
+
+    fn push(&self, line: ConsoleLine) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Returns up to `count` of the most recent lines, oldest first.
+    pub fn tail(&self, count: usize) -> Vec<ConsoleLine> {
+        let lines = self.lines.lock().unwrap();
+        lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect()
+    }
+}
+
+/// Log file that is rotated once it reaches a maximum size: `FILE` is moved to
+/// `FILE.1`, `FILE.1` to `FILE.2` and so on, dropping the oldest.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), file, size, max_size, max_files })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |index: usize| PathBuf::from(format!("{}.{index}", self.path.display()));
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = rotated(index);
+                if from.exists() {
+                    fs::rename(&from, rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Where a [`LogWriter`] writes console lines to.
+trait LineSink: Send + 'static {
+    /// Name used in warnings and for the writer thread.
+    const NAME: &'static str;
+
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+}
+
+impl LineSink for RotatingFile {
+    const NAME: &'static str = "console-log";
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// The launcher's stdout.
+struct StdoutSink;
+
+impl LineSink for StdoutSink {
+    const NAME: &'static str = "console-stdout";
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(io::stdout().lock(), "{line}")
+    }
+}
+
+/// Writes console lines to a [`LineSink`] on a dedicated thread, so that a
+/// slow disk or a blocked stdout doesn't hold up reading the guest output, and
+/// with it the detection of guest failures. Lines still queued are written out
+/// when the writer is dropped.
+struct LogWriter {
+    name: &'static str,
+    lines: Option<mpsc::SyncSender<String>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LogWriter {
+    fn spawn<S: LineSink>(mut sink: S) -> io::Result<Self> {
+        let (lines, queued) = mpsc::sync_channel::<String>(LOG_QUEUE_LINES);
+        let thread = thread::Builder::new().name(String::from(S::NAME)).spawn(move || {
+            for line in queued {
+                if let Err(err) = sink.write_line(&line) {
+                    log::warn!("couldn't write to {}: {}", S::NAME, err);
+                }
+            }
+        })?;
+        Ok(Self { name: S::NAME, lines: Some(lines), thread: Some(thread) })
+    }
+
+    fn write_line(&self, line: String) {
+        let Some(lines) = &self.lines else {
+            return;
+        };
+        match lines.try_send(line) {
+            Ok(()) => {}
+            Err(mpsc::TrySendError::Full(_)) => {
+                log::warn!("{} is falling behind, dropped a line", self.name)
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                log::warn!("{} writer has stopped, dropped a line", self.name)
+            }
+        }
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        // Closing the queue lets the thread finish the remaining lines and exit.
+        self.lines.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Console capture shared by all guests started by the launcher.
+#[derive(Clone)]
+pub struct Console {
+    buffer: ConsoleBuffer,
+    stdout: Arc<LogWriter>,
+    log_file: Option<Arc<LogWriter>>,
+    patterns: Arc<[(ConsoleEventKind, String)]>,
+    events: broadcast::Sender<ConsoleEvent>,
+    /// Raw output, for clients attached to the console socket.
+    output: broadcast::Sender<Arc<[u8]>>,
+    /// Input side of the current guest's console.
+    input: Arc<tokio::sync::Mutex<Option<OwnedWriteHalf>>>,
+}
+
+impl Console {
+    pub fn new(config: &ConsoleConfig) -> Result<Self> {
+        let stdout = LogWriter::spawn(StdoutSink)
+            .context("couldn't start the console stdout writer")?
+            .into();
+        let log_file = config
+            .log_file
+            .as_deref()
+            .map(|path| {
+                RotatingFile::open(path, config.max_file_bytes, config.max_files)
+                    .with_context(|| format!("couldn't open console log {}", path.display()))
+            })
+            .transpose()?
+            .map(LogWriter::spawn)
+            .transpose()
+            .context("couldn't start the console log writer")?
+            .map(Arc::new);
+        let patterns = DEFAULT_FATAL_PATTERNS
+            .iter()
+            .map(|(kind, pattern)| (*kind, pattern.to_string()))
+            .chain(
+                config
+                    .fatal_patterns
+                    .iter()
+                    .map(|pattern| (ConsoleEventKind::Fatal, pattern.clone())),
+            )
+            .collect();
+        let (events, _) = broadcast::channel(16);
+        let (output, _) = broadcast::channel(256);
+        Ok(Self {
+            buffer: ConsoleBuffer::new(config.buffer_lines),
+            stdout,
+            log_file,
+            patterns,
+            events,
+            output,
+            input: Default::default(),
+        })
+    }
+
+    /// Returns a receiver for the failures recognised from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConsoleEvent> {
+        self.events.subscribe()
+    }
+
+    /// Returns the buffer holding the most recent console lines.
+    pub fn buffer(&self) -> &ConsoleBuffer {
+        &self.buffer
+    }
+
+    /// Creates a new console stream for a guest and starts capturing
+    /// everything written to it. Input from attached clients goes to the most
+    /// recently attached guest.
+    pub async fn attach(&self) -> Result<UnixStream> {
+        let (guest_console, host_console) = UnixStream::pair()?;
+        host_console.set_nonblocking(true)?;
+        let (mut reader, writer) = tokio::net::UnixStream::from_std(host_console)?.into_split();
+        // The previous guest is gone by now, so its input can be dropped.
+        *self.input.lock().await = Some(writer);
+
+        let console = self.clone();
+        tokio::spawn(async move {
+            let mut chunk = vec![0; 4096];
+            let mut pending = Vec::new();
+            loop {
+                match reader.read(&mut chunk).await {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        // Nobody may be attached, which is fine.
+                        let _ = console.output.send(chunk[..len].into());
+                        pending.extend_from_slice(&chunk[..len]);
+                        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                            console.record(&pending.drain(..=end).collect::<Vec<_>>());
+                        }
+                        if pending.len() >= MAX_LINE_LENGTH {
+                            console.record(&std::mem::take(&mut pending));
+                        }
+                    }
+                    Err(err) => {
+                        log::warn!("couldn't read from guest console: {}", err);
+                        break;
+                    }
+                }
+            }
+            if !pending.is_empty() {
+                console.record(&pending);
+            }
+        });
+
+        Ok(guest_console)
+    }
+
+    /// Sends input to the guest's console.
+    async fn write_input(&self, bytes: &[u8]) -> Result<()> {
+        let mut input = self.input.lock().await;
+        let writer = input.as_mut().context("no guest console is connected")?;
+        writer.write_all(bytes).await.context("couldn't write to guest console")
+    }
+
+    fn record(&self, bytes: &[u8]) {
+        let text = String::from_utf8_lossy(bytes).trim_end_matches(['\r', '\n']).to_string();
+        let line = ConsoleLine { timestamp: SystemTime::now(), text };
+        let formatted = line.to_string();
+
+        if let Some(log_file) = &self.log_file {
+            log_file.write_line(formatted.clone());
+        }
+        self.stdout.write_line(formatted);
+        METRICS.record_guest_sample(&line.text);
+        if let Some(event) = self.match_event(&line) {
+            log::error!("{}", event);
+            // Nobody may be listening, which is fine.
+            let _ = self.events.send(event);
+        }
+        self.buffer.push(line);
+    }
+
+    fn match_event(&self, line: &ConsoleLine) -> Option<ConsoleEvent> {
+        let text = line.text.to_lowercase();
+        self.patterns.iter().find(|(_, pattern)| text.contains(&pattern.to_lowercase())).map(
+            |(kind, pattern)| ConsoleEvent {
+                kind: *kind,
+                pattern: pattern.clone(),
+                line: line.clone(),
+            },
+        )
+    }
+}
+
+/// The guest console, served on a Unix domain socket. Stops serving and removes
+/// the socket when dropped.
+pub struct ConsoleSocket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl ConsoleSocket {
+    pub fn bind(console: &Console, path: &Path) -> Result<Self> {
+        remove_stale_socket(path)?;
+        let listener = tokio::net::UnixListener::bind(path)
+            .with_context(|| format!("failed to bind console socket {}", path.display()))?;
+        println!("console listening on {}", path.display());
+
+        let console = console.clone();
+        let task = tokio::spawn(async move {
+            let mut clients = JoinSet::new();
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            log::info!("console client attached");
+                            clients.spawn(serve_console_client(console.clone(), stream));
+                        }
+                        Err(err) => log::warn!("failed to accept console client: {}", err),
+                    },
+                    Some(_) = clients.join_next() => log::info!("console client detached"),
+                }
+            }
+        });
+
+        Ok(Self { path: path.to_path_buf(), task })
+    }
+}
+
+impl Drop for ConsoleSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Streams the guest output to a client and forwards its input to the guest,
+/// until either side goes away.
+async fn serve_console_client(console: Console, stream: tokio::net::UnixStream) {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut output = console.output.subscribe();
+
+    let forward_output = async {
+        loop {
+            match output.recv().await {
+                Ok(bytes) => {
+                    if writer.write_all(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    log::warn!("console client fell behind, dropped {} chunks", count);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    };
+    let forward_input = async {
+        let mut buffer = [0; 1024];
+        loop {
+            match reader.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(len) => {
+                    if let Err(err) = console.write_input(&buffer[..len]).await {
+                        log::warn!("{:?}", err);
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = forward_output => {}
+        _ = forward_input => {}
+    }
+}
+
+/// Formats a timestamp as UTC in RFC 3339 format with millisecond precision.
+pub fn format_timestamp(timestamp: SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Converts days since the epoch to a (year, month, day) civil date, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A directory for the test's files, removed again when the test ends.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("console-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn line(text: &str) -> ConsoleLine {
+        ConsoleLine { timestamp: UNIX_EPOCH, text: String::from(text) }
+    }
+
+    fn texts(lines: Vec<ConsoleLine>) -> Vec<String> {
+        lines.into_iter().map(|line| line.text).collect()
+    }
+
+    fn console(fatal_patterns: &[&str]) -> Console {
+        Console::new(&ConsoleConfig {
+            log_file: None,
+            max_file_bytes: 0,
+            max_files: 0,
+            buffer_lines: 0,
+            fatal_patterns: fatal_patterns.iter().map(|pattern| pattern.to_string()).collect(),
+        })
+        .unwrap()
+    }
+
+    fn event_kind(console: &Console, text: &str) -> Option<ConsoleEventKind> {
+        console.match_event(&line(text)).map(|event| event.kind)
+    }
+
+    #[test]
+    fn normal_guest_output_isnt_fatal() {
+        let console = console(&[]);
+        for text in [
+            "orchestrator INFO: Received 0 bytes of endorsement data",
+            "orchestrator INFO: Finished setup, handing off executing to the app and going to sleep.",
+            "kernel DEBUG: Extending kernel heap to [0xffffc90000000000..0xffffc90000600000).",
+            "kernel DEBUG: Writing new frame to Cr3: PhysFrame[4KiB](0x1806000)",
+            "INFO: In main!",
+            "DEBUG: waiting for a request message",
+            "DEBUG: received request message with invocation id 42 (21 bytes)",
+            "DEBUG: sending response message with invocation id 42 (117 bytes)",
+            "WARN: dropping malformed request message: invalid frame length",
+            "WARN: host went away (channel closed); waiting for it to reconnect",
+            "INFO: host reconnected",
+        ] {
+            assert_eq!(event_kind(&console, text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn guest_failures_are_recognised() {
+        let console = console(&[]);
+        for (text, kind) in [
+            ("ERROR: panicked at ledger/src/main.rs:72:10:", ConsoleEventKind::Panic),
+            (
+                "kernel ERROR: panicked at oak_restricted_kernel/src/lib.rs:519:5:",
+                ConsoleEventKind::Panic,
+            ),
+            (
+                "server encountered an unrecoverable error: couldn't receive message",
+                ConsoleEventKind::Fatal,
+            ),
+        ] {
+            assert_eq!(event_kind(&console, text), Some(kind), "{text}");
+        }
+    }
+
+    #[test]
+    fn extra_fatal_patterns_ignore_case() {
+        let console = console(&["Ledger Corrupted"]);
+        assert_eq!(
+            event_kind(&console, "ERROR: ledger corrupted at block 17"),
+            Some(ConsoleEventKind::Fatal)
+        );
+    }
+
+    #[test]
+    fn civil_from_days_around_the_epoch() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(365), (1971, 1, 1));
+    }
+
+    #[test]
+    fn civil_from_days_in_leap_years() {
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-135_081), (1600, 2, 29));
+        // 2100 is divisible by 100 but not 400, so it isn't a leap year.
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn format_timestamp_at_the_epoch() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        // Earlier times can't be represented and are clamped to the epoch.
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH - Duration::from_secs(1)),
+            "1970-01-01T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn format_timestamp_with_time_of_day() {
+        let timestamp = UNIX_EPOCH + Duration::from_millis(951_868_799_999);
+        assert_eq!(format_timestamp(timestamp), "2000-02-29T23:59:59.999Z");
+    }
+
+    #[test]
+    fn buffer_keeps_the_most_recent_lines() {
+        let buffer = ConsoleBuffer::new(3);
+        for text in ["one", "two", "three", "four", "five"] {
+            buffer.push(line(text));
+        }
+        assert_eq!(texts(buffer.tail(10)), ["three", "four", "five"]);
+        assert_eq!(texts(buffer.tail(2)), ["four", "five"]);
+        assert!(buffer.tail(0).is_empty());
+    }
+
+    #[test]
+    fn buffer_without_capacity_keeps_nothing() {
+        let buffer = ConsoleBuffer::new(0);
+        buffer.push(line("one"));
+        assert!(buffer.tail(10).is_empty());
+    }
+
+    #[test]
+    fn rotating_file_rotates_at_the_size_limit() {
+        let dir = TestDir::new("rotate");
+        let path = dir.0.join("console.log");
+        let read =
+            |suffix: &str| fs::read_to_string(format!("{}{suffix}", path.display())).unwrap();
+
+        // Each line takes 6 bytes with its line ending, so two fit in 12 bytes.
+        let mut file = RotatingFile::open(&path, 12, 2).unwrap();
+        for text in ["line1", "line2", "line3", "line4", "line5", "line6", "line7"] {
+            file.write_line(text).unwrap();
+        }
+
+        assert_eq!(read(""), "line7\n");
+        assert_eq!(read(".1"), "line5\nline6\n");
+        assert_eq!(read(".2"), "line3\nline4\n");
+        assert!(!PathBuf::from(format!("{}.3", path.display())).exists());
+    }
+
+    #[test]
+    fn rotating_file_without_history_truncates() {
+        let dir = TestDir::new("truncate");
+        let path = dir.0.join("console.log");
+
+        let mut file = RotatingFile::open(&path, 12, 0).unwrap();
+        for text in ["line1", "line2", "line3"] {
+            file.write_line(text).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line3\n");
+        assert!(!PathBuf::from(format!("{}.1", path.display())).exists());
+    }
+
+    #[test]
+    fn log_writer_writes_queued_lines_before_it_stops() {
+        let dir = TestDir::new("writer");
+        let path = dir.0.join("console.log");
+
+        let writer = LogWriter::spawn(RotatingFile::open(&path, 1024, 1).unwrap()).unwrap();
+        for text in ["line1", "line2", "line3"] {
+            writer.write_line(String::from(text));
+        }
+        drop(writer);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line1\nline2\nline3\n");
+    }
+}
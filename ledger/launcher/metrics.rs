@@ -0,0 +1,301 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/metrics.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Prometheus metrics, served in the text exposition format by the admin API
+//! at `/metrics`.
+//!
+//! Bridge traffic is counted per bridge in [`BridgeStats`]; everything else is
+//! recorded in the process-wide [`METRICS`].
+//!
+//! The guest can export its own samples, such as the request durations
+//! `start_blocking_server` records in its `SampleStore`, by writing console
+//! lines of the form `oak_sample <name> <value>...`. They are exported as the
+//! `launcher_guest_sample` histogram, labelled by name.
+//!
+//! Label values come from the guest and from clients, so only a fixed set of
+//! them is kept apart; anything else is recorded under [`OTHER_LABEL`] to keep
+//! the number of series bounded.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use super::bridge::BridgeStats;
+
+/// Bucket bounds in seconds for request latencies.
+pub const LATENCY_BUCKETS: &[f64] =
+    &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Bucket bounds in seconds for boot phases, which can take a while under TCG.
+const BOOT_PHASE_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Bucket bounds for guest samples. Their unit is up to the guest; timer
+/// samples are in TSC ticks.
+const GUEST_SAMPLE_BUCKETS: &[f64] = &[1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10];
+
+/// Marker that starts a guest sample in a console line.
+const GUEST_SAMPLE_MARKER: &str = "oak_sample ";
+
+/// Names of the guest samples that get their own series.
+const GUEST_SAMPLE_NAMES: &[&str] = &["request_ticks"];
+
+/// Label for values outside the known set.
+const OTHER_LABEL: &str = "other";
+
+/// Returns `value` if it's one of `known`, or [`OTHER_LABEL`].
+pub fn bounded_label<'a>(value: &'a str, known: &[&str]) -> &'a str {
+    if known.contains(&value) {
+        value
+    } else {
+        OTHER_LABEL
+    }
+}
+
+/// Metrics recorded outside of the bridge.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    /// Initial data transfers to the guest that failed.
+    pub send_raw_failures: AtomicU64,
+    pub guest_restarts: AtomicU64,
+    /// Time spent in each phase of starting a guest, see [`super::boot`].
+    pub boot_phase_duration: HistogramFamily,
+    pub guest_samples: HistogramFamily,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            send_raw_failures: AtomicU64::new(0),
+            guest_restarts: AtomicU64::new(0),
+            boot_phase_duration: HistogramFamily::new(BOOT_PHASE_BUCKETS),
+            guest_samples: HistogramFamily::new(GUEST_SAMPLE_BUCKETS),
+        }
+    }
+
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the guest samples the console line carries, if any.
+    pub fn record_guest_sample(&self, line: &str) {
+        let Some((_, sample)) = line.split_once(GUEST_SAMPLE_MARKER) else {
+            return;
+        };
+        let mut fields = sample.split_whitespace();
+        let Some(name) = fields.next() else {
+            log::debug!("ignoring malformed guest sample: {}", sample);
+            return;
+        };
+        let name = bounded_label(name, GUEST_SAMPLE_NAMES);
+        for value in fields {
+            match value.parse::<f64>() {
+                Ok(value) => self.guest_samples.observe(name, value),
+                Err(_) => log::debug!("ignoring malformed guest sample value: {}", value),
+            }
+        }
+    }
+}
+
+/// Histograms with shared bucket bounds, keyed by the value of one label.
+pub struct HistogramFamily {
+    buckets: &'static [f64],
+    histograms: Mutex<BTreeMap<String, Histogram>>,
+}
+
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last one is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl HistogramFamily {
+    pub const fn new(buckets: &'static [f64]) -> Self {
+        Self { buckets, histograms: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn observe(&self, label: &str, value: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms
+            .entry(label.to_string())
+            .or_insert_with(|| Histogram { counts: vec![0; self.buckets.len() + 1], sum: 0.0 });
+        let bucket = self.buckets.iter().position(|bound| value <= *bound);
+        histogram.counts[bucket.unwrap_or(self.buckets.len())] += 1;
+        histogram.sum += value;
+    }
+
+    pub fn observe_duration(&self, label: &str, duration: Duration) {
+        self.observe(label, duration.as_secs_f64());
+    }
+}
+
+/// Builds a text exposition.
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: &AtomicU64) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.0, "{name} {}", value.load(Ordering::Relaxed));
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: &AtomicU64) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.0, "{name} {}", value.load(Ordering::Relaxed));
+    }
+
+    fn histograms(&mut self, name: &str, help: &str, label: &str, family: &HistogramFamily) {
+        self.header(name, "histogram", help);
+        for (value, histogram) in family.histograms.lock().unwrap().iter() {
+            let value = escape_label(value);
+            let mut cumulative = 0;
+            for (index, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                let bound = family.buckets.get(index).map_or("+Inf".to_string(), f64::to_string);
+                let _ = writeln!(
+                    self.0,
+                    "{name}_bucket{{{label}=\"{value}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(self.0, "{name}_sum{{{label}=\"{value}\"}} {}", histogram.sum);
+            let _ = writeln!(self.0, "{name}_count{{{label}=\"{value}\"}} {cumulative}");
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+/// Renders all metrics, including those of the bridge if there is one.
+pub fn render(bridge: Option<&BridgeStats>) -> String {
+    let mut out = Exposition::default();
+    if let Some(bridge) = bridge {
+        out.counter(
+            "launcher_bridge_connections_accepted_total",
+            "Bridge connections accepted.",
+            &bridge.connections_accepted,
+        );
+        out.counter(
+            "launcher_bridge_connections_rejected_total",
+            "Bridge connections closed because too many were open.",
+            &bridge.connections_rejected,
+        );
+        out.counter(
+            "launcher_bridge_accept_errors_total",
+            "Errors accepting bridge connections.",
+            &bridge.accept_errors,
+        );
+        out.gauge(
+            "launcher_bridge_connections_active",
+            "Bridge connections currently open.",
+            &bridge.connections_active,
+        );
+        out.counter(
+            "launcher_bridge_requests_total",
+            "Requests forwarded to the guest.",
+            &bridge.requests,
+        );
+        out.counter(
+            "launcher_bridge_request_errors_total",
+            "Requests the guest couldn't be invoked for.",
+            &bridge.request_errors,
+        );
+        out.counter(
+            "launcher_bridge_received_bytes_total",
+            "Request bytes forwarded from clients to the guest.",
+            &bridge.bytes_received,
+        );
+        out.counter(
+            "launcher_bridge_sent_bytes_total",
+            "Response bytes forwarded from the guest to clients.",
+            &bridge.bytes_sent,
+        );
+        out.histograms(
+            "launcher_bridge_request_duration_seconds",
+            "Time from forwarding a request to receiving its response, by micro RPC method.",
+            "method",
+            &bridge.request_duration,
+        );
+    }
+    out.counter(
+        "launcher_send_raw_failures_total",
+        "Failed attempts to send the initial data to the guest.",
+        &METRICS.send_raw_failures,
+    );
+    out.counter("launcher_guest_restarts_total", "Guest restarts.", &METRICS.guest_restarts);
+    out.histograms(
+        "launcher_boot_phase_duration_seconds",
+        "Time spent in each phase of starting the guest.",
+        "phase",
+        &METRICS.boot_phase_duration,
+    );
+    out.histograms(
+        "launcher_guest_sample",
+        "Samples reported by the guest on its console.",
+        "name",
+        &METRICS.guest_samples,
+    );
+    out.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the number of observations per label.
+    fn counts(family: &HistogramFamily) -> Vec<(String, u64)> {
+        let histograms = family.histograms.lock().unwrap();
+        histograms
+            .iter()
+            .map(|(label, histogram)| (label.clone(), histogram.counts.iter().sum()))
+            .collect()
+    }
+
+    #[test]
+    fn guest_samples_are_read_from_batches() {
+        let metrics = Metrics::new();
+        metrics.record_guest_sample("INFO: oak_sample request_ticks 1200 3400 bogus 5600");
+        metrics.record_guest_sample("DEBUG: waiting for a request message");
+        assert_eq!(counts(&metrics.guest_samples), [(String::from("request_ticks"), 3)]);
+    }
+
+    #[test]
+    fn unknown_guest_sample_names_share_a_label() {
+        let metrics = Metrics::new();
+        metrics.record_guest_sample("oak_sample first 1");
+        metrics.record_guest_sample("oak_sample second 2");
+        metrics.record_guest_sample("oak_sample");
+        assert_eq!(counts(&metrics.guest_samples), [(String::from(OTHER_LABEL), 2)]);
+    }
+
+    #[test]
+    fn bounded_labels() {
+        assert_eq!(bounded_label("2", &["1", "2"]), "2");
+        assert_eq!(bounded_label("3", &["1", "2"]), OTHER_LABEL);
+    }
+}
@@ -0,0 +1,174 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/native.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Runs the guest directly as a host process instead of inside a VM.
+//!
+//! This is useful for exercising the launcher, the bridge and clients on
+//! machines without `/dev/kvm`. The guest gets no isolation whatsoever, so this
+//! must never be used for anything but testing.
+
+use std::{
+    net::Shutdown,
+    os::{fd::OwnedFd, unix::net::UnixStream},
+    path::Path,
+    process::Stdio,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use command_fds::{CommandFdExt, FdMapping};
+use log::info;
+use oak_proto_rust::oak::attestation::v1::Evidence;
+use oak_restricted_kernel_interface::OAK_CHANNEL_FD;
+
+use super::{
+    boot::{BootTimeouts, BootTracker},
+    initial_data, load_guest,
+    transport::{HostSocket, Transport},
+    vmm::shell_quote,
+    GuestInstance, Params,
+};
+
+/// Guest running as a plain Linux process.
+///
+/// The process gets the guest end of a socketpair as its communication channel
+/// on file descriptor [`OAK_CHANNEL_FD`], the same number the restricted kernel
+/// uses, and its stdin, stdout and stderr are connected to the guest console.
+pub struct NativeInstance {
+    guest_console: UnixStream,
+    host_socket: HostSocket,
+    instance: tokio::process::Child,
+    evidence: Option<Evidence>,
+}
+
+impl NativeInstance {
+    /// Starts the native binary with given parameters and stream to write
+    /// console logs to.
+    ///
+    /// Just like the VM instance, the initial data is sent over the channel
+    /// once the process is running, and the process is started with the
+    /// `kill_on_drop` flag set, and the boot phases are recorded in `boot`.
+    pub fn start(params: Params, guest_console: UnixStream, boot: &BootTracker) -> Result<Self> {
+        let native_binary = native_binary(&params)?;
+        let initial_data_bytes = initial_data(&params)?;
+
+        let (guest_socket, host_socket) = UnixStream::pair()?;
+        let mut host_socket = HostSocket::Unix(host_socket);
+
+        let mut cmd = tokio::process::Command::new(native_binary);
+        cmd.stdin(Stdio::from(OwnedFd::from(guest_console.try_clone()?)));
+        cmd.stdout(Stdio::from(OwnedFd::from(guest_console.try_clone()?)));
+        cmd.stderr(Stdio::from(OwnedFd::from(guest_console.try_clone()?)));
+        cmd.fd_mappings(vec![FdMapping {
+            parent_fd: guest_socket.into(),
+            child_fd: OAK_CHANNEL_FD,
+        }])
+        .context("couldn't map the guest socket into the child process")?;
+        cmd.kill_on_drop(true);
+
+        info!("executing: {:?}", cmd);
+
+        let instance = cmd
+            .spawn()
+            .with_context(|| format!("couldn't start native binary {}", native_binary.display()))?;
+
+        let evidence = match initial_data_bytes {
+            Some(initial_data_bytes) => load_guest(
+                &mut host_socket,
+                &initial_data_bytes,
+                params.evidence_output.as_deref(),
+                boot,
+                &BootTimeouts::from_params(&params),
+                false,
+            )?,
+            None => None,
+        };
+
+        Ok(Self { guest_console, host_socket, instance, evidence })
+    }
+}
+
+/// Returns the binary to run, checking that the parameters suit native mode.
+fn native_binary(params: &Params) -> Result<&Path> {
+    let native_binary = params
+        .native_binary
+        .as_deref()
+        .context("--native-binary is required when launching in native mode")?;
+    if params.transport != Transport::VirtioSerial {
+        anyhow::bail!("native mode only supports the default transport");
+    }
+    Ok(native_binary)
+}
+
+/// Returns the command line that [`NativeInstance::start`] would execute for
+/// the given parameters, noting where the guest's channel and console go.
+pub fn command_line(params: &Params) -> Result<String> {
+    let native_binary = native_binary(params)?;
+    Ok(format!(
+        "{} # channel on fd {} (OAK_CHANNEL_FD), console on stdin, stdout and stderr",
+        shell_quote(&native_binary.to_string_lossy()),
+        OAK_CHANNEL_FD
+    ))
+}
+
+#[async_trait]
+impl GuestInstance for NativeInstance {
+    async fn wait(&mut self) -> Result<std::process::ExitStatus> {
+        info!("waiting for native guest to terminate");
+        self.instance.wait().await.map_err(anyhow::Error::from)
+    }
+
+    async fn kill(mut self: Box<Self>) -> Result<std::process::ExitStatus> {
+        info!("killing native guest; cleaning up and shutting down");
+        self.guest_console.shutdown(Shutdown::Both)?;
+        self.instance.start_kill()?;
+        self.wait().await
+    }
+
+    async fn connect(&self) -> Result<Box<dyn oak_channel::Channel>> {
+        info!("connecting to native guest");
+        Ok(Box::new(self.host_socket.try_clone()?))
+    }
+
+    fn evidence(&self) -> Option<&Evidence> {
+        self.evidence.as_ref()
+    }
+
+    fn pid(&self) -> Option<u32> {
+        self.instance.id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn command_line_names_the_channel_fd() {
+        let params =
+            Params::try_parse_from(["launcher", "--mode=native", "--native-binary=/bin/sh"])
+                .unwrap();
+        assert_eq!(
+            command_line(&params).unwrap(),
+            format!(
+                "/bin/sh # channel on fd {OAK_CHANNEL_FD} (OAK_CHANNEL_FD), console on stdin, \
+                 stdout and stderr"
+            )
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/qmp.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Client for the QEMU Machine Protocol (QMP), the JSON control interface QEMU
+//! serves on the socket passed as [`GuestFds::qmp`](super::vmm::GuestFds).
+//!
+//! QEMU greets every new connection and only accepts commands once the client
+//! has negotiated capabilities, which the client does on first use. Messages
+//! are single-line JSON documents; asynchronous events can arrive at any time
+//! and are logged and otherwise ignored.
+//!
+//! Every command carries an `id` that QEMU copies into its reply. A command
+//! whose future is dropped before the reply arrives, e.g. because of a timeout,
+//! leaves its reply on the connection; the next command skips it by its `id`.
+
+use std::{fmt, os::unix::net, path::Path, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+    sync::Mutex,
+};
+
+/// Run state of the VM, as reported by `query-status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmStatus {
+    /// QEMU's run state, e.g. `running`, `paused` or `shutdown`.
+    pub status: String,
+    pub running: bool,
+}
+
+/// Connection to QEMU's QMP monitor. Cheap to clone; commands from all clones
+/// are serialised over the one connection.
+#[derive(Clone)]
+pub struct QmpClient {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl fmt::Debug for QmpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QmpClient").finish_non_exhaustive()
+    }
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    greeted: bool,
+    negotiated: bool,
+    /// Id of the last command sent.
+    last_id: u64,
+    /// Start of a message whose read was cancelled.
+    line: Vec<u8>,
+    /// Set while a command is being written, so that a write that was cancelled
+    /// halfway is noticed.
+    interrupted: bool,
+}
+
+/// A command as sent to QEMU.
+#[derive(Serialize)]
+struct Command<'a> {
+    execute: &'a str,
+    id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arguments: Option<Value>,
+}
+
+impl QmpClient {
+    /// Wraps the launcher's end of the QMP socket.
+    pub fn new(socket: net::UnixStream) -> Result<Self> {
+        socket.set_nonblocking(true)?;
+        let (reader, writer) = UnixStream::from_std(socket)?.into_split();
+        Ok(Self {
+            connection: Arc::new(Mutex::new(Connection {
+                reader: BufReader::new(reader),
+                writer,
+                greeted: false,
+                negotiated: false,
+                last_id: 0,
+                line: Vec::new(),
+                interrupted: false,
+            })),
+        })
+    }
+
+    /// Runs a QMP command and returns its `return` value.
+    pub async fn execute(&self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut connection = self.connection.lock().await;
+        if !connection.greeted {
+            let greeting = connection.receive().await.context("no QMP greeting")?;
+            if greeting.get("QMP").is_none() {
+                bail!("unexpected QMP greeting: {greeting}");
+            }
+            connection.greeted = true;
+        }
+        if !connection.negotiated {
+            let id = connection.send("qmp_capabilities", None).await?;
+            // Once sent, the negotiation can't be repeated; if this is cancelled the
+            // reply is skipped like any other stale one.
+            connection.negotiated = true;
+            connection.reply(id).await?;
+        }
+        connection
+            .request(command, arguments)
+            .await
+            .with_context(|| format!("QMP {command} failed"))
+    }
+
+    /// Returns the run state of the VM.
+    pub async fn query_status(&self) -> Result<VmStatus> {
+        let status = self.execute("query-status", None).await?;
+        serde_json::from_value(status).context("invalid query-status reply")
+    }
+
+    /// Pauses the guest's vCPUs.
+    pub async fn pause(&self) -> Result<()> {
+        self.execute("stop", None).await.map(drop)
+    }
+
+    /// Resumes a paused guest.
+    pub async fn resume(&self) -> Result<()> {
+        self.execute("cont", None).await.map(drop)
+    }
+
+    /// Resets the VM as if the reset button was pressed.
+    pub async fn system_reset(&self) -> Result<()> {
+        self.execute("system_reset", None).await.map(drop)
+    }
+
+    /// Asks QEMU to exit.
+    pub async fn quit(&self) -> Result<()> {
+        self.execute("quit", None).await.map(drop)
+    }
+
+    /// Writes the guest memory to `path` as an ELF core file. Returns once the
+    /// dump is complete.
+    pub async fn dump_guest_memory(&self, path: &Path) -> Result<()> {
+        let arguments = json!({
+            "paging": false,
+            "protocol": format!("file:{}", path.display()),
+        });
+        self.execute("dump-guest-memory", Some(arguments)).await.map(drop)
+    }
+}
+
+impl Connection {
+    async fn request(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let id = self.send(command, arguments).await?;
+        self.reply(id).await
+    }
+
+    /// Sends a command and returns its id.
+    async fn send(&mut self, command: &str, arguments: Option<Value>) -> Result<u64> {
+        if self.interrupted {
+            bail!("QMP connection is unusable after an interrupted command");
+        }
+        self.last_id += 1;
+        let mut request =
+            serde_json::to_vec(&Command { execute: command, id: self.last_id, arguments })?;
+        request.push(b'\n');
+        self.interrupted = true;
+        self.writer.write_all(&request).await?;
+        self.interrupted = false;
+        Ok(self.last_id)
+    }
+
+    /// Waits for the reply to the command with the given id.
+    async fn reply(&mut self, id: u64) -> Result<Value> {
+        loop {
+            let response = self.receive().await?;
+            if response.get("return").is_none() && response.get("error").is_none() {
+                match response.get("event").and_then(Value::as_str) {
+                    Some(event) => log::info!("QMP event {}", event),
+                    None => log::warn!("unexpected QMP message: {}", response),
+                }
+                continue;
+            }
+            if response.get("id").and_then(Value::as_u64) != Some(id) {
+                log::debug!("skipping reply to an abandoned QMP command: {}", response);
+                continue;
+            }
+            if let Some(error) = response.get("error") {
+                let description = error.get("desc").and_then(Value::as_str).unwrap_or("unknown");
+                bail!("{description}");
+            }
+            return Ok(response.get("return").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    async fn receive(&mut self) -> Result<Value> {
+        // Unlike `read_line`, `read_until` keeps what it has read in `self.line` if
+        // it's cancelled, so a later call picks up where it left off.
+        self.reader.read_until(b'\n', &mut self.line).await?;
+        if self.line.last() != Some(&b'\n') {
+            bail!("QMP connection closed");
+        }
+        let line = std::mem::take(&mut self.line);
+        serde_json::from_slice(&line).context("invalid QMP message")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const GREETING: &str = r#"{"QMP": {"version": {}, "capabilities": []}}"#;
+
+    /// Plays QEMU on the other end of the QMP socket.
+    struct FakeQemu {
+        reader: BufReader<OwnedReadHalf>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl FakeQemu {
+        async fn send(&mut self, message: &str) {
+            self.writer.write_all(message.as_bytes()).await.unwrap();
+        }
+
+        /// Reads the next command and returns its name and id.
+        async fn command(&mut self) -> (String, Value) {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).await.unwrap();
+            let command: Value = serde_json::from_str(&line).unwrap();
+            let name = command.get("execute").and_then(Value::as_str).unwrap().to_string();
+            (name, command.get("id").cloned().unwrap_or(Value::Null))
+        }
+
+        async fn reply(&mut self, id: &Value, value: &str) {
+            self.send(&format!("{{\"return\": {value}, \"id\": {id}}}\n")).await;
+        }
+
+        async fn expect(&mut self, name: &str) -> Value {
+            let (command, id) = self.command().await;
+            assert_eq!(command, name);
+            id
+        }
+
+        async fn negotiate(&mut self) {
+            self.send(&format!("{GREETING}\n")).await;
+            let id = self.expect("qmp_capabilities").await;
+            self.reply(&id, "{}").await;
+        }
+    }
+
+    fn connect() -> (QmpClient, FakeQemu) {
+        let (client, server) = net::UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let (reader, writer) = UnixStream::from_std(server).unwrap().into_split();
+        (QmpClient::new(client).unwrap(), FakeQemu { reader: BufReader::new(reader), writer })
+    }
+
+    #[tokio::test]
+    async fn query_status_skips_events() {
+        let (qmp, mut qemu) = connect();
+        let qemu = async {
+            qemu.negotiate().await;
+            let id = qemu.expect("query-status").await;
+            qemu.send("{\"event\": \"STOP\", \"timestamp\": {}}\n").await;
+            qemu.reply(&id, r#"{"status": "paused", "running": false}"#).await;
+        };
+
+        let (status, ()) = tokio::join!(qmp.query_status(), qemu);
+        assert_eq!(status.unwrap(), VmStatus { status: String::from("paused"), running: false });
+    }
+
+    #[tokio::test]
+    async fn system_reset_sends_the_command() {
+        let (qmp, mut qemu) = connect();
+        let qemu = async {
+            qemu.negotiate().await;
+            let mut line = String::new();
+            qemu.reader.read_line(&mut line).await.unwrap();
+            let command: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(command, json!({"execute": "system_reset", "id": 2}));
+            qemu.reply(&command["id"], "{}").await;
+        };
+
+        let (reset, ()) = tokio::join!(qmp.system_reset(), qemu);
+        reset.unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors_are_returned() {
+        let (qmp, mut qemu) = connect();
+        let qemu = async {
+            qemu.negotiate().await;
+            let id = qemu.expect("cont").await;
+            qemu.send(&format!(
+                "{{\"error\": {{\"class\": \"GenericError\", \"desc\": \"no VM\"}}, \"id\": {id}}}\n"
+            ))
+            .await;
+        };
+
+        let (resumed, ()) = tokio::join!(qmp.resume(), qemu);
+        assert!(format!("{:#}", resumed.unwrap_err()).contains("no VM"));
+    }
+
+    #[tokio::test]
+    async fn reply_to_cancelled_command_is_skipped() {
+        let (qmp, mut qemu) = connect();
+        let (paused, stop) =
+            tokio::join!(tokio::time::timeout(Duration::from_millis(50), qmp.pause()), async {
+                qemu.negotiate().await;
+                qemu.expect("stop").await
+            });
+        assert!(paused.is_err(), "pause should have timed out");
+
+        let qemu = async {
+            let id = qemu.expect("cont").await;
+            // The late reply to `stop` must not be taken for the reply to `cont`.
+            qemu.send(&format!(
+                "{{\"error\": {{\"class\": \"GenericError\", \"desc\": \"late\"}}, \"id\": {stop}}}\n"
+            ))
+            .await;
+            qemu.reply(&id, "{}").await;
+        };
+        let (resumed, ()) = tokio::join!(qmp.resume(), qemu);
+        resumed.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_read_keeps_the_partial_message() {
+        let (qmp, mut qemu) = connect();
+        let (paused, stop) =
+            tokio::join!(tokio::time::timeout(Duration::from_millis(50), qmp.pause()), async {
+                qemu.negotiate().await;
+                let id = qemu.expect("stop").await;
+                qemu.send("{\"return\": {}, ").await;
+                id
+            });
+        assert!(paused.is_err(), "pause should have timed out");
+
+        let qemu = async {
+            qemu.send(&format!("\"id\": {stop}}}\n")).await;
+            let id = qemu.expect("cont").await;
+            qemu.reply(&id, "{}").await;
+        };
+        let (resumed, ()) = tokio::join!(qmp.resume(), qemu);
+        resumed.unwrap();
+    }
+}
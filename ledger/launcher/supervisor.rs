@@ -0,0 +1,450 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/supervisor.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Keeps the guest running according to a restart policy.
+
+use std::{
+    fs,
+    os::unix::process::ExitStatusExt,
+    path::Path,
+    process::ExitStatus,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use log::info;
+use oak_proto_rust::oak::attestation::v1::Evidence;
+use prost::Message;
+use tokio::sync::{broadcast, mpsc, watch};
+
+use super::{
+    admin::{AdminCommand, AdminContext, AdminServer, GuestState, GuestStatus},
+    boot::BootTracker,
+    bridge::{Bridge, BridgeAddress},
+    console::{Console, ConsoleBuffer, ConsoleEvent, ConsoleSocket, FatalAction},
+    metrics::{Metrics, METRICS},
+    qmp::QmpClient,
+    start_guest, GuestInstance, Params,
+};
+
+/// When to restart the guest after it exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RestartPolicy {
+    /// Never restart the guest.
+    #[default]
+    Never,
+    /// Restart the guest if it exits with a failure status.
+    OnFailure,
+    /// Restart the guest whenever it exits.
+    Always,
+}
+
+impl RestartPolicy {
+    fn should_restart(self, status: &ExitStatus) -> bool {
+        match self {
+            Self::Never => false,
+            Self::OnFailure => !status.success(),
+            Self::Always => true,
+        }
+    }
+}
+
+/// Settings for restarting and stopping the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    /// Delay before the first restart; doubles with every consecutive restart.
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between restarts.
+    pub max_backoff: Duration,
+    /// A guest that stayed up for at least this long is considered healthy and
+    /// resets the backoff.
+    pub healthy_uptime: Duration,
+    /// Number of restarts after which the supervisor gives up, or `None` to
+    /// keep restarting forever.
+    pub max_restarts: Option<u32>,
+    /// How long to wait for in-flight bridge requests when the guest is killed.
+    pub shutdown_timeout: Duration,
+}
+
+impl RestartConfig {
+    pub fn from_params(params: &Params) -> Self {
+        Self {
+            policy: params.restart_policy,
+            initial_backoff: Duration::from_millis(params.restart_backoff_ms),
+            max_backoff: Duration::from_millis(params.restart_backoff_max_ms),
+            healthy_uptime: Duration::from_millis(params.restart_healthy_ms),
+            max_restarts: params.max_restarts,
+            shutdown_timeout: Duration::from_millis(params.shutdown_timeout_ms),
+        }
+    }
+
+    /// Delay before the given consecutive restart (starting at 0).
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_backoff)
+    }
+
+    /// Returns the attempt to continue the backoff from when a guest that was
+    /// up for `uptime` exits.
+    fn next_attempt(&self, uptime: Duration, attempt: u32) -> u32 {
+        if uptime >= self.healthy_uptime {
+            0
+        } else {
+            attempt
+        }
+    }
+
+    fn budget_exhausted(&self, restarts: u32) -> bool {
+        self.max_restarts.is_some_and(|max_restarts| restarts >= max_restarts)
+    }
+}
+
+/// Guest instance that is restarted according to a [`RestartConfig`] when it
+/// exits, together with the bridge that forwards client messages to it.
+///
+/// A restarted guest goes through the full startup again: it is sent the
+/// initial data and, if enabled, exchanges evidence. The bridge stays bound in
+/// the meantime and holds requests until the new guest is connected.
+pub struct Supervisor {
+    params: Params,
+    config: RestartConfig,
+    guest: Option<Box<dyn GuestInstance + Send + Sync>>,
+    started_at: Instant,
+    last_status: Option<ExitStatus>,
+    bridge: Option<Bridge>,
+    console: Console,
+    _console_socket: Option<ConsoleSocket>,
+    boot: BootTracker,
+    events: broadcast::Receiver<ConsoleEvent>,
+    status: watch::Sender<GuestStatus>,
+    commands: (mpsc::Sender<AdminCommand>, mpsc::Receiver<AdminCommand>),
+    admin: Option<AdminServer>,
+    restarts: u32,
+    /// Restarts requested through the admin API, which don't count toward
+    /// [`RestartConfig::max_restarts`].
+    operator_restarts: u32,
+}
+
+impl Supervisor {
+    /// Supervises an already started guest.
+    pub(super) fn new(
+        params: Params,
+        guest: Box<dyn GuestInstance + Send + Sync>,
+        bridge: Option<Bridge>,
+        console: Console,
+        console_socket: Option<ConsoleSocket>,
+        boot: BootTracker,
+    ) -> Self {
+        let status = GuestStatus {
+            state: GuestState::Running,
+            pid: guest.pid(),
+            started_at: Instant::now(),
+            restarts: 0,
+            operator_restarts: 0,
+            evidence: guest.evidence().map(|evidence| evidence.encode_to_vec().into()),
+            last_exit: None,
+            qmp: guest.qmp().cloned(),
+        };
+        Self {
+            config: RestartConfig::from_params(&params),
+            params,
+            guest: Some(guest),
+            started_at: status.started_at,
+            last_status: None,
+            bridge,
+            events: console.subscribe(),
+            console,
+            _console_socket: console_socket,
+            boot,
+            status: watch::channel(status).0,
+            commands: mpsc::channel(1),
+            admin: None,
+            restarts: 0,
+            operator_restarts: 0,
+        }
+    }
+
+    /// Returns how many times the guest has been restarted.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Serves the admin API on the given address for as long as the supervisor
+    /// runs.
+    pub(super) async fn serve_admin(&mut self, address: &BridgeAddress) -> Result<()> {
+        let context = AdminContext {
+            guest: self.status.subscribe(),
+            commands: self.commands.0.clone(),
+            console: self.console.buffer().clone(),
+            boot: self.boot.clone(),
+            bridge_addresses: self
+                .bridge
+                .iter()
+                .flat_map(|bridge| bridge.addresses())
+                .cloned()
+                .collect(),
+            bridge_stats: self.bridge.as_ref().map(Bridge::stats),
+        };
+        self.admin = Some(AdminServer::start(address, context).await?);
+        Ok(())
+    }
+
+    /// Publishes the current state of the guest to the admin API.
+    fn publish(&self, state: GuestState) {
+        let guest = self.guest.as_ref();
+        self.status.send_replace(GuestStatus {
+            state,
+            pid: guest.and_then(|guest| guest.pid()),
+            started_at: self.started_at,
+            restarts: self.restarts,
+            operator_restarts: self.operator_restarts,
+            evidence: guest
+                .and_then(|guest| guest.evidence())
+                .map(|evidence| evidence.encode_to_vec().into()),
+            last_exit: self.last_status.map(|status| status.to_string()),
+            qmp: guest.and_then(|guest| guest.qmp()).cloned(),
+        });
+    }
+
+    /// Shuts down the bridge, letting in-flight requests finish, and then the
+    /// guest.
+    async fn stop(&mut self) -> Result<ExitStatus> {
+        if let Some(bridge) = self.bridge.take() {
+            bridge.shutdown(self.config.shutdown_timeout).await;
+        }
+        let status = match self.guest.take() {
+            Some(guest) => guest.kill().await?,
+            None => {
+                info!("no guest instance is running");
+                self.last_status.context("guest instance never ran")?
+            }
+        };
+        self.last_status = Some(status);
+        self.publish(GuestState::Stopped);
+        Ok(status)
+    }
+
+    /// Waits for the guest console to report a failure.
+    async fn next_fatal_event(events: &mut broadcast::Receiver<ConsoleEvent>) -> ConsoleEvent {
+        loop {
+            match events.recv().await {
+                Ok(event) => return event,
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Writes the memory of the current guest to a new file in `dir`.
+    async fn dump_guest_memory(&self, dir: &Path) -> Result<()> {
+        let qmp = self
+            .guest
+            .as_ref()
+            .and_then(|guest| guest.qmp())
+            .context("can't dump guest memory without QMP")?;
+        fs::create_dir_all(dir)
+            .with_context(|| format!("couldn't create crash dump directory {}", dir.display()))?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = dir.join(format!("guest-memory-{}.elf", timestamp.as_secs()));
+        log::warn!("dumping guest memory to {}", path.display());
+        qmp.dump_guest_memory(&path).await
+    }
+
+    /// Disconnects the bridge from the guest, which has to be gone already.
+    fn prepare_restart(&mut self) {
+        if let Some(bridge) = &self.bridge {
+            bridge.disconnect_guest();
+        }
+        // Make sure the old guest is gone before starting a new one.
+        self.guest = None;
+        self.publish(GuestState::Restarting);
+    }
+
+    /// Starts a new guest and connects the bridge to it.
+    async fn start(&mut self) -> Result<()> {
+        let (guest, connector_handle) =
+            start_guest(self.params.clone(), &self.console, &self.boot).await?;
+        if let Some(bridge) = &self.bridge {
+            bridge.connect_guest(connector_handle);
+        }
+        self.guest = Some(guest);
+        self.started_at = Instant::now();
+        self.publish(GuestState::Running);
+        Ok(())
+    }
+
+    /// Replaces the guest on behalf of the admin API. This is a single attempt
+    /// outside the restart budget; if it fails the guest is treated as having
+    /// exited, so the restart policy decides what happens next.
+    async fn operator_restart(&mut self) -> Result<Option<ExitStatus>> {
+        if let Some(guest) = self.guest.take() {
+            self.last_status = Some(guest.kill().await?);
+        }
+        self.prepare_restart();
+        self.operator_restarts += 1;
+        log::warn!("restarting guest on request (operator restart {})", self.operator_restarts);
+        match self.start().await {
+            Ok(()) => Ok(None),
+            Err(err) => {
+                log::error!("failed to restart guest: {:?}", err);
+                Ok(Some(self.last_status.context("guest instance never ran")?))
+            }
+        }
+    }
+
+    /// Keeps trying to start a new guest, backing off between attempts, until
+    /// one starts or the restart budget runs out. Returns the attempt the
+    /// backoff continues from, which counts the failed attempts as well.
+    async fn restart(&mut self, mut attempt: u32) -> Result<u32> {
+        self.prepare_restart();
+
+        loop {
+            if self.config.budget_exhausted(self.restarts) {
+                anyhow::bail!("giving up after {} restarts", self.restarts);
+            }
+            let delay = self.config.backoff(attempt);
+            attempt += 1;
+            self.restarts += 1;
+            Metrics::increment(&METRICS.guest_restarts);
+            log::warn!("restarting guest in {:?} (restart {})", delay, self.restarts);
+            tokio::time::sleep(delay).await;
+
+            match self.start().await {
+                Ok(()) => return Ok(attempt),
+                Err(err) => log::error!("failed to restart guest: {:?}", err),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl GuestInstance for Supervisor {
+    async fn wait(&mut self) -> Result<ExitStatus> {
+        let mut attempt = 0;
+        loop {
+            // A panic usually spans several matching lines; only dump once.
+            let mut dumped = false;
+            let status = loop {
+                let guest = self.guest.as_mut().context("no guest instance is running")?;
+                tokio::select! {
+                    status = guest.wait() => break status?,
+                    event = Self::next_fatal_event(&mut self.events) => {
+                        if let (Some(dir), false) = (&self.params.crash_dump_dir, dumped) {
+                            dumped = true;
+                            if let Err(err) = self.dump_guest_memory(dir).await {
+                                log::error!("{:?}", err);
+                            }
+                        }
+                        if self.params.on_guest_fatal == FatalAction::Kill {
+                            // Fail fast rather than restarting into the same failure.
+                            if let Some(guest) = self.guest.take() {
+                                self.last_status = Some(guest.kill().await?);
+                            }
+                            self.publish(GuestState::Stopped);
+                            anyhow::bail!("{}", event);
+                        }
+                    }
+                    Some(command) = self.commands.1.recv() => match command {
+                        AdminCommand::Restart => match self.operator_restart().await? {
+                            None => dumped = false,
+                            Some(status) => break status,
+                        },
+                        AdminCommand::Shutdown => {
+                            self.stop().await?;
+                            info!("guest shut down on request");
+                            // A requested shutdown is a success, unlike a SIGTERM.
+                            return Ok(ExitStatus::from_raw(0));
+                        }
+                    },
+                }
+            };
+            self.last_status = Some(status);
+            if !self.config.policy.should_restart(&status) {
+                self.guest = None;
+                self.publish(GuestState::Stopped);
+                return Ok(status);
+            }
+            log::warn!("guest instance exited with {}", status);
+
+            attempt = self.config.next_attempt(self.started_at.elapsed(), attempt);
+            attempt = match self.restart(attempt).await {
+                Ok(attempt) => attempt,
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    self.publish(GuestState::Stopped);
+                    return Ok(status);
+                }
+            };
+        }
+    }
+
+    async fn kill(mut self: Box<Self>) -> Result<ExitStatus> {
+        self.stop().await
+    }
+
+    async fn connect(&self) -> Result<Box<dyn oak_channel::Channel>> {
+        self.guest.as_ref().context("no guest instance is running")?.connect().await
+    }
+
+    fn evidence(&self) -> Option<&Evidence> {
+        self.guest.as_ref().and_then(|guest| guest.evidence())
+    }
+
+    fn pid(&self) -> Option<u32> {
+        self.guest.as_ref().and_then(|guest| guest.pid())
+    }
+
+    fn qmp(&self) -> Option<&QmpClient> {
+        self.guest.as_ref().and_then(|guest| guest.qmp())
+    }
+
+    fn console(&self) -> Option<&ConsoleBuffer> {
+        Some(self.console.buffer())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RestartConfig {
+        RestartConfig {
+            policy: RestartPolicy::Always,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            healthy_uptime: Duration::from_secs(60),
+            max_restarts: None,
+            shutdown_timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = config();
+        let delays: Vec<_> = (0..5).map(|attempt| config.backoff(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn backoff_resets_only_after_the_healthy_uptime() {
+        let config = config();
+        // Longer than the maximum backoff, but not healthy yet.
+        assert_eq!(config.next_attempt(Duration::from_secs(10), 3), 3);
+        assert_eq!(config.next_attempt(Duration::from_secs(60), 3), 0);
+    }
+}
@@ -0,0 +1,356 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/transport.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Host side of the communication channel to the guest.
+//!
+//! By default the channel is a socketpair backing a virtio-serial console
+//! device. With [`Transport::Vsock`] the VM instead gets a vhost-vsock device
+//! and the launcher connects to the guest over `AF_VSOCK`, which has no
+//! single-port limit and doesn't go through QEMU's chardev layer.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+/// Port the restricted kernel listens on for the vsock channel.
+pub const DEFAULT_VSOCK_PORT: u32 = 1024;
+
+/// Device used to reserve guest CIDs.
+const VHOST_VSOCK_DEVICE: &str = "/dev/vhost-vsock";
+
+/// `VHOST_VSOCK_SET_GUEST_CID`, i.e. `_IOW(VHOST_VIRTIO, 0x60, __u64)`.
+const VHOST_VSOCK_SET_GUEST_CID: u64 = 0x4008_af60;
+
+/// CIDs 0 to 2 are reserved for the hypervisor and the host.
+pub const FIRST_GUEST_CID: u32 = 3;
+
+/// How many CIDs to try before giving up on finding a free one.
+const CID_ATTEMPTS: u32 = 1024;
+
+/// How often to retry connecting while the guest is still booting.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Largest write a [`DeadlineSocket`] makes at once. A socket's send timeout
+/// only bounds each wait for buffer space, so a single large write to a guest
+/// that keeps reading slowly could run far past the deadline.
+const MAX_DEADLINE_WRITE: usize = 64 * 1024;
+
+/// How the launcher talks to the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+    /// A virtio-serial console device backed by a socketpair.
+    #[default]
+    VirtioSerial,
+    /// A vhost-vsock device, connected to over `AF_VSOCK`.
+    Vsock,
+}
+
+/// Finds a guest CID that no other VM on this host is using.
+///
+/// The CID is only checked, not held, so another VM could still take it before
+/// ours starts; starting from a number derived from our PID makes that
+/// unlikely.
+pub fn allocate_cid() -> Result<u32> {
+    let device =
+        fs::OpenOptions::new().read(true).write(true).open(VHOST_VSOCK_DEVICE).with_context(
+            || format!("couldn't open {VHOST_VSOCK_DEVICE}, is the vhost_vsock module loaded?"),
+        )?;
+    let start = std::process::id() % (u32::MAX - FIRST_GUEST_CID);
+    for offset in 0..CID_ATTEMPTS {
+        let cid = FIRST_GUEST_CID + start.wrapping_add(offset) % (u32::MAX - FIRST_GUEST_CID);
+        let cid_arg = u64::from(cid);
+        // Claiming the CID on our own vhost device fails if another VM has it.
+        // Closing the device releases it again.
+        // SAFETY: the request takes a pointer to a u64, which outlives the call.
+        let result = unsafe {
+            libc::ioctl(device.as_raw_fd(), VHOST_VSOCK_SET_GUEST_CID as _, &cid_arg as *const u64)
+        };
+        if result == 0 {
+            return Ok(cid);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EADDRINUSE) {
+            return Err(err).context("couldn't check guest CID");
+        }
+    }
+    bail!("couldn't find a free guest CID after {CID_ATTEMPTS} attempts")
+}
+
+/// A connected `AF_VSOCK` stream socket.
+#[derive(Debug)]
+pub struct VsockStream {
+    fd: OwnedFd,
+}
+
+impl VsockStream {
+    /// Connects to a port on the given CID.
+    pub fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        // SAFETY: plain syscall without pointers.
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just created and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: all-zero is a valid `sockaddr_vm`.
+        let mut address: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        address.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        address.svm_cid = cid;
+        address.svm_port = port;
+        // SAFETY: the address points to a `sockaddr_vm` of the given size.
+        let result = unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd })
+    }
+
+    /// Keeps trying to connect until the guest listens on the port or the
+    /// deadline passes.
+    pub fn connect_with_retry(cid: u32, port: u32, deadline: Instant) -> Result<Self> {
+        loop {
+            match Self::connect(cid, port) {
+                Ok(stream) => return Ok(stream),
+                Err(err) if Instant::now() < deadline => {
+                    log::debug!("guest vsock port isn't ready yet: {}", err);
+                    thread::sleep(CONNECT_RETRY_INTERVAL);
+                }
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("couldn't connect to vsock {cid}:{port}"))
+                }
+            }
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self { fd: self.fd.try_clone()? })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_timeout(libc::SO_RCVTIMEO, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_timeout(libc::SO_SNDTIMEO, timeout)
+    }
+
+    fn set_timeout(&self, option: libc::c_int, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.unwrap_or_default();
+        let timeval = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        // SAFETY: the option value points to a `timeval` of the given size.
+        let result = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                option,
+                &timeval as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: the buffer is valid for writes of its length.
+        let len = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: the buffer is valid for reads of its length.
+        let len = unsafe { libc::write(self.fd.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The launcher's end of the communication channel.
+#[derive(Debug)]
+pub enum HostSocket {
+    Unix(UnixStream),
+    Vsock(VsockStream),
+}
+
+impl HostSocket {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            HostSocket::Unix(stream) => stream.try_clone().map(HostSocket::Unix),
+            HostSocket::Vsock(stream) => stream.try_clone().map(HostSocket::Vsock),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            HostSocket::Unix(stream) => stream.set_read_timeout(timeout),
+            HostSocket::Vsock(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            HostSocket::Unix(stream) => stream.set_write_timeout(timeout),
+            HostSocket::Vsock(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+/// A [`HostSocket`] whose reads and writes fail once a deadline has passed,
+/// however many of them a transfer takes. The socket's timeouts are cleared
+/// again when this is dropped.
+pub struct DeadlineSocket<'a> {
+    socket: &'a mut HostSocket,
+    deadline: Instant,
+}
+
+impl<'a> DeadlineSocket<'a> {
+    pub fn new(socket: &'a mut HostSocket, deadline: Instant) -> Self {
+        Self { socket, deadline }
+    }
+
+    /// Returns the time left until the deadline, as a socket timeout.
+    fn time_left(&self) -> io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            // A zero timeout would mean no timeout at all.
+            Some(left) if !left.is_zero() => Ok(left.max(Duration::from_millis(1))),
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed")),
+        }
+    }
+}
+
+impl Read for DeadlineSocket<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.set_read_timeout(Some(self.time_left()?))?;
+        self.socket.read(buf)
+    }
+}
+
+impl Write for DeadlineSocket<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.set_write_timeout(Some(self.time_left()?))?;
+        self.socket.write(&buf[..buf.len().min(MAX_DEADLINE_WRITE)])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl Drop for DeadlineSocket<'_> {
+    fn drop(&mut self) {
+        let _ = self.socket.set_read_timeout(None);
+        let _ = self.socket.set_write_timeout(None);
+    }
+}
+
+impl Read for HostSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HostSocket::Unix(stream) => stream.read(buf),
+            HostSocket::Vsock(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for HostSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            HostSocket::Unix(stream) => stream.write(buf),
+            HostSocket::Vsock(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            HostSocket::Unix(stream) => stream.flush(),
+            HostSocket::Vsock(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_applies_to_the_whole_transfer() {
+        let (host, mut guest) = UnixStream::pair().unwrap();
+        // A guest that keeps reading, just slowly, so that no single write waits
+        // for long.
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            while guest.read(&mut buffer).is_ok_and(|len| len > 0) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let mut host = HostSocket::Unix(host);
+        let started = Instant::now();
+        let result = DeadlineSocket::new(&mut host, started + Duration::from_millis(200))
+            .write_all(&vec![0; 64 * 1024 * 1024]);
+
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+        let HostSocket::Unix(stream) = &host else { unreachable!() };
+        assert_eq!(stream.write_timeout().unwrap(), None);
+    }
+
+    #[test]
+    fn passed_deadline_fails_right_away() {
+        let (host, _guest) = UnixStream::pair().unwrap();
+        let mut host = HostSocket::Unix(host);
+        let mut socket = DeadlineSocket::new(&mut host, Instant::now());
+        let err = socket.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,397 @@
+//
+// Copyright 2025 The Project Oak Authors // oak/oak_launcher_utils/src/launcher/vmm.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! VMM backends that describe how to start the VM as plain data, so that the
+//! exact command line can be inspected and tested without booting anything.
+
+use std::{
+    fs,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use super::Params;
+
+/// File descriptors, open in the launcher, that the VMM should use to talk to
+/// the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestFds {
+    /// Socket the first serial port (the guest console) is routed to.
+    pub console: RawFd,
+    /// Device backing the communication channel.
+    pub comms: Comms,
+    /// Socket to serve the VMM's control protocol on, if it has one.
+    pub qmp: Option<RawFd>,
+}
+
+/// Device the guest's communication channel runs over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comms {
+    /// A virtio-serial console backed by the given socket.
+    VirtioSerial(RawFd),
+    /// A vhost-vsock device with the given guest CID.
+    Vsock { cid: u32 },
+}
+
+/// CPU model used unless configured otherwise. Needed to expose advanced CPU
+/// features, specifically RDRAND which is required for remote attestation.
+pub const DEFAULT_CPU_MODEL: &str = "IvyBridge-IBRS";
+
+/// Machine type used unless configured otherwise: the `microvm` machine with
+/// ACPI enabled.
+pub const DEFAULT_MACHINE_TYPE: &str = "microvm,acpi=on";
+
+/// Device that has to be accessible for hardware acceleration.
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// Which accelerator to ask for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum AccelMode {
+    /// Use KVM if `/dev/kvm` is accessible, and fall back to TCG otherwise.
+    #[default]
+    Auto,
+    /// Always use KVM.
+    Kvm,
+    /// Always use TCG software emulation.
+    Tcg,
+}
+
+/// Accelerator the VM runs with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Accel {
+    Kvm,
+    Tcg,
+}
+
+impl AccelMode {
+    /// Picks the accelerator, checking whether KVM is usable in auto mode.
+    pub fn resolve(self) -> Accel {
+        match self {
+            Self::Kvm => Accel::Kvm,
+            Self::Tcg => Accel::Tcg,
+            Self::Auto => match fs::OpenOptions::new().read(true).write(true).open(KVM_DEVICE) {
+                Ok(_) => Accel::Kvm,
+                Err(err) => {
+                    log::warn!(
+                        "{KVM_DEVICE} is not accessible ({err}), falling back to TCG; the \
+                             guest will run considerably slower"
+                    );
+                    Accel::Tcg
+                }
+            },
+        }
+    }
+}
+
+/// A virtual machine monitor that can run the guest.
+pub trait VmmBackend: Send + Sync {
+    /// Path to the VMM binary to execute.
+    fn binary(&self) -> &Path;
+
+    /// Command-line arguments for the VMM, wired up to the given file
+    /// descriptors.
+    fn args(&self, fds: GuestFds) -> Vec<String>;
+
+    /// Whether the VMM can be controlled over QMP on [`GuestFds::qmp`].
+    fn supports_qmp(&self) -> bool {
+        false
+    }
+}
+
+/// Builds the command line for QEMU.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Qemu {
+    pub binary: PathBuf,
+    pub bios_binary: PathBuf,
+    pub kernel: PathBuf,
+    pub initrd: PathBuf,
+    pub memory_size: Option<String>,
+    pub gdb: Option<u16>,
+    pub pci_passthrough: Option<String>,
+    pub accel: Accel,
+    pub cpu_model: String,
+    pub machine_type: String,
+    pub vcpus: Option<u16>,
+}
+
+impl Qemu {
+    /// Takes the QEMU settings from the launcher parameters.
+    pub fn from_params(params: &Params) -> Result<Self> {
+        Ok(Self {
+            binary: required_in_vm_mode(&params.vmm_binary, "--vmm-binary")?,
+            bios_binary: required_in_vm_mode(&params.bios_binary, "--bios-binary")?,
+            kernel: required_in_vm_mode(&params.kernel, "--kernel")?,
+            initrd: required_in_vm_mode(&params.initrd, "--initrd")?,
+            memory_size: params.memory_size.clone(),
+            gdb: params.gdb,
+            pci_passthrough: params.pci_passthrough.clone(),
+            accel: params.accel.resolve(),
+            cpu_model: params.cpu_model.clone(),
+            machine_type: params.machine_type.clone(),
+            vcpus: params.vcpus,
+        })
+    }
+
+    /// Suffix of the virtio devices to use: `microvm` only has virtio-mmio,
+    /// while the other machine types need the PCI variants.
+    fn virtio_transport(&self) -> &'static str {
+        match self.machine_type.split(',').next() {
+            Some("microvm") => "device",
+            _ => "pci",
+        }
+    }
+}
+
+/// Unwraps a parameter that's only optional outside of VM mode.
+fn required_in_vm_mode(param: &Option<PathBuf>, name: &str) -> Result<PathBuf> {
+    param.clone().with_context(|| format!("{name} is required when launching a VM"))
+}
+
+impl VmmBackend for Qemu {
+    fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    fn args(&self, fds: GuestFds) -> Vec<String> {
+        let mut args: Vec<String> = Vec::new();
+        let mut push = |values: &[&str]| args.extend(values.iter().map(|value| value.to_string()));
+
+        match self.accel {
+            Accel::Kvm => push(&["-enable-kvm"]),
+            Accel::Tcg => push(&["-accel", "tcg"]),
+        }
+        // Log guest errors and other interesting events to stderr.
+        push(&["-d", "int,unimp,guest_errors"]);
+        push(&["-cpu", self.cpu_model.as_str()]);
+        if let Some(vcpus) = self.vcpus {
+            push(&["-smp", vcpus.to_string().as_str()]);
+        }
+        // Set memory size if given.
+        if let Some(memory_size) = &self.memory_size {
+            push(&["-m", memory_size.as_str()]);
+        };
+        // Disable a bunch of hardware we don't need.
+        push(&["-nodefaults"]);
+        push(&["-nographic"]);
+        // If the VM restarts, don't restart it (we're not expecting any restarts so any
+        // restart should be treated as a failure)
+        push(&["-no-reboot"]);
+        push(&["-machine", self.machine_type.as_str()]);
+        // Route first serial port to console.
+        push(&["-chardev", format!("socket,id=consock,fd={}", fds.console).as_str()]);
+        push(&["-serial", "chardev:consock"]);
+        // Add the virtio device.
+        let virtio = self.virtio_transport();
+        match fds.comms {
+            Comms::VirtioSerial(fd) => {
+                push(&["-chardev", format!("socket,id=commsock,fd={fd}").as_str()]);
+                push(&["-device", format!("virtio-serial-{virtio},max_ports=1").as_str()]);
+                push(&["-device", "virtconsole,chardev=commsock"]);
+            }
+            Comms::Vsock { cid } => {
+                push(&["-device", format!("vhost-vsock-{virtio},guest-cid={cid}").as_str()]);
+            }
+        }
+        // Serve QMP so that the launcher can query and control the VM.
+        if let Some(qmp) = fds.qmp {
+            push(&["-chardev", format!("socket,id=qmpsock,fd={qmp}").as_str()]);
+            push(&["-mon", "chardev=qmpsock,mode=control"]);
+        }
+        if let Some(pci_passthrough) = &self.pci_passthrough {
+            push(&["-device", format!("vfio-pci,host={pci_passthrough}").as_str()]);
+        }
+        // Use stage0 as the BIOS.
+        push(&["-bios", self.bios_binary.to_string_lossy().as_ref()]);
+        // stage0 accoutrements: kernel that's compatible with the linux boot protocol
+        push(&["-kernel", self.kernel.to_string_lossy().as_ref()]);
+
+        if let Some(gdb_port) = self.gdb {
+            // Listen for a gdb connection on the provided port and wait for debugger before
+            // booting
+            push(&["-gdb", format!("tcp::{gdb_port}").as_str()]);
+            push(&["-S"]);
+        }
+
+        push(&["-initrd", self.initrd.to_string_lossy().as_ref()]);
+
+        args
+    }
+
+    fn supports_qmp(&self) -> bool {
+        true
+    }
+}
+
+/// Renders the VMM invocation as a single shell command line, quoting
+/// arguments where needed so that it can be copied and run as-is.
+pub fn command_line(vmm: &dyn VmmBackend, fds: GuestFds) -> String {
+    std::iter::once(vmm.binary().to_string_lossy().into_owned())
+        .chain(vmm.args(fds))
+        .map(|arg| shell_quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Quotes the argument for a POSIX shell, unless it doesn't need it.
+pub(crate) fn shell_quote(arg: &str) -> String {
+    let is_safe = !arg.is_empty()
+        && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_=,.:/@%+".contains(c));
+    if is_safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FDS: GuestFds = GuestFds { console: 7, comms: Comms::VirtioSerial(9), qmp: None };
+
+    fn qemu() -> Qemu {
+        Qemu {
+            binary: PathBuf::from("/usr/bin/qemu-system-x86_64"),
+            bios_binary: PathBuf::from("/artifacts/stage0_bin"),
+            kernel: PathBuf::from("/artifacts/kernel"),
+            initrd: PathBuf::from("/artifacts/oak_orchestrator"),
+            memory_size: None,
+            gdb: None,
+            pci_passthrough: None,
+            accel: Accel::Kvm,
+            cpu_model: String::from(DEFAULT_CPU_MODEL),
+            machine_type: String::from(DEFAULT_MACHINE_TYPE),
+            vcpus: None,
+        }
+    }
+
+    const BASE: &str = "/usr/bin/qemu-system-x86_64 -enable-kvm -d int,unimp,guest_errors \
+                        -cpu IvyBridge-IBRS";
+    const DEVICES: &str = "-nodefaults -nographic -no-reboot -machine microvm,acpi=on \
+                           -chardev socket,id=consock,fd=7 -serial chardev:consock \
+                           -chardev socket,id=commsock,fd=9 \
+                           -device virtio-serial-device,max_ports=1 \
+                           -device virtconsole,chardev=commsock";
+    const IMAGES: &str = "-bios /artifacts/stage0_bin -kernel /artifacts/kernel";
+    const INITRD: &str = "-initrd /artifacts/oak_orchestrator";
+
+    #[test]
+    fn default_command_line() {
+        assert_eq!(command_line(&qemu(), FDS), format!("{BASE} {DEVICES} {IMAGES} {INITRD}"));
+    }
+
+    #[test]
+    fn command_line_with_memory_size() {
+        let vmm = Qemu { memory_size: Some(String::from("8G")), ..qemu() };
+        assert_eq!(command_line(&vmm, FDS), format!("{BASE} -m 8G {DEVICES} {IMAGES} {INITRD}"));
+    }
+
+    #[test]
+    fn command_line_with_gdb() {
+        let vmm = Qemu { gdb: Some(1234), ..qemu() };
+        assert_eq!(
+            command_line(&vmm, FDS),
+            format!("{BASE} {DEVICES} {IMAGES} -gdb tcp::1234 -S {INITRD}")
+        );
+    }
+
+    #[test]
+    fn command_line_with_pci_passthrough() {
+        let vmm = Qemu { pci_passthrough: Some(String::from("0000:01:00.0")), ..qemu() };
+        assert_eq!(
+            command_line(&vmm, FDS),
+            format!("{BASE} {DEVICES} -device vfio-pci,host=0000:01:00.0 {IMAGES} {INITRD}")
+        );
+    }
+
+    #[test]
+    fn command_line_with_all_options() {
+        let vmm = Qemu {
+            memory_size: Some(String::from("256M")),
+            gdb: Some(4321),
+            pci_passthrough: Some(String::from("0000:02:00.1")),
+            ..qemu()
+        };
+        assert_eq!(
+            command_line(&vmm, FDS),
+            format!(
+                "{BASE} -m 256M {DEVICES} -device vfio-pci,host=0000:02:00.1 {IMAGES} \
+                 -gdb tcp::4321 -S {INITRD}"
+            )
+        );
+    }
+
+    #[test]
+    fn command_line_with_vm_shape() {
+        let vmm = Qemu {
+            accel: Accel::Tcg,
+            cpu_model: String::from("max"),
+            machine_type: String::from("q35"),
+            vcpus: Some(4),
+            ..qemu()
+        };
+        assert_eq!(
+            command_line(&vmm, FDS),
+            "/usr/bin/qemu-system-x86_64 -accel tcg -d int,unimp,guest_errors -cpu max -smp 4 \
+             -nodefaults -nographic -no-reboot -machine q35 -chardev socket,id=consock,fd=7 \
+             -serial chardev:consock -chardev socket,id=commsock,fd=9 \
+             -device virtio-serial-pci,max_ports=1 -device virtconsole,chardev=commsock \
+             -bios /artifacts/stage0_bin -kernel /artifacts/kernel \
+             -initrd /artifacts/oak_orchestrator"
+        );
+    }
+
+    #[test]
+    fn command_line_with_qmp() {
+        let fds = GuestFds { qmp: Some(11), ..FDS };
+        assert_eq!(
+            command_line(&qemu(), fds),
+            format!(
+                "{BASE} {DEVICES} -chardev socket,id=qmpsock,fd=11 \
+                 -mon chardev=qmpsock,mode=control {IMAGES} {INITRD}"
+            )
+        );
+    }
+
+    #[test]
+    fn command_line_with_vsock() {
+        let fds = GuestFds { comms: Comms::Vsock { cid: 42 }, ..FDS };
+        assert_eq!(
+            command_line(&qemu(), fds),
+            format!(
+                "{BASE} -nodefaults -nographic -no-reboot -machine microvm,acpi=on \
+                 -chardev socket,id=consock,fd=7 -serial chardev:consock \
+                 -device vhost-vsock-device,guest-cid=42 {IMAGES} {INITRD}"
+            )
+        );
+    }
+
+    #[test]
+    fn command_line_with_vsock_on_pci() {
+        let vmm = Qemu { machine_type: String::from("pc"), ..qemu() };
+        let fds = GuestFds { comms: Comms::Vsock { cid: 42 }, ..FDS };
+        assert!(command_line(&vmm, fds).contains("-device vhost-vsock-pci,guest-cid=42"));
+    }
+
+    #[test]
+    fn command_line_quotes_unsafe_arguments() {
+        let vmm = Qemu { kernel: PathBuf::from("/my artifacts/it's a kernel"), ..qemu() };
+        assert!(command_line(&vmm, FDS).contains(r"-kernel '/my artifacts/it'\''s a kernel'"));
+    }
+}
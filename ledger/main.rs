@@ -0,0 +1,146 @@
+//
+// Copyright 2022 The Project Oak Authors // oak/oak_restricted_kernel_launcher/src/main.rs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{ExitCode, ExitStatus},
+};
+
+use clap::{Parser, Subcommand};
+use oak_launcher_utils::launcher;
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::UnixStream,
+    signal::unix::{signal, Signal, SignalKind},
+};
+
+#[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    launcher_params: launcher::Params,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Attaches to the console of a running launcher started with
+    /// `--console-socket`.
+    Attach {
+        /// The launcher's console socket.
+        socket: PathBuf,
+    },
+}
+
+/// Connects stdin and stdout to a launcher's console socket until either side
+/// closes.
+async fn attach(socket: &Path) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let stream = UnixStream::connect(socket).await?;
+    eprintln!("attached to {}, press Ctrl-C to detach", socket.display());
+    let (mut reader, mut writer) = stream.into_split();
+    let (mut stdin, mut stdout) = (io::stdin(), io::stdout());
+    tokio::select! {
+        copied = io::copy(&mut reader, &mut stdout) => {
+            copied?;
+        }
+        copied = io::copy(&mut stdin, &mut writer) => {
+            copied?;
+            writer.shutdown().await?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Termination signals the launcher shuts down gracefully on.
+struct ShutdownSignals {
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl ShutdownSignals {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Waits for the next signal and returns its name and number.
+    async fn recv(&mut self) -> (&'static str, i32) {
+        tokio::select! {
+            _ = self.interrupt.recv() => ("SIGINT", SignalKind::interrupt().as_raw_value()),
+            _ = self.terminate.recv() => ("SIGTERM", SignalKind::terminate().as_raw_value()),
+        }
+    }
+}
+
+/// Exits with the guest's status, or `128 + signal` if it was killed by one.
+fn exit_code(status: ExitStatus) -> ExitCode {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => ExitCode::from(code as u8),
+        (None, Some(signal)) => ExitCode::from(128 + signal as u8),
+        (None, None) => ExitCode::FAILURE,
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    env_logger::init();
+    let cli = Args::parse();
+    if let Some(Command::Attach { socket }) = &cli.command {
+        return attach(socket).await;
+    }
+    log::debug!("launcher params: {:?}", cli.launcher_params);
+
+    if cli.launcher_params.dry_run {
+        println!("{}", launcher::dry_run_command(&cli.launcher_params)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Install the handlers before launching, so that a signal during startup
+    // doesn't bypass the cleanup below.
+    let mut signals = ShutdownSignals::new()?;
+    let (mut guest_instance, _connector_handle) = launcher::launch(cli.launcher_params).await?;
+
+    // Keep running until the guest exits or we're asked to stop.
+    let (name, number) = tokio::select! {
+        status = guest_instance.wait() => {
+            let status = status?;
+            log::info!("guest instance exited with {}", status);
+            log::logger().flush();
+            return Ok(exit_code(status));
+        }
+        signal = signals.recv() => signal,
+    };
+
+    // Stop accepting bridge connections, let in-flight requests finish and stop
+    // the guest. A second signal skips the wait; dropping the instance still
+    // kills the VMM process.
+    log::info!("received {}, shutting down", name);
+    tokio::select! {
+        status = guest_instance.kill() => match status {
+            Ok(status) => log::info!("guest instance stopped with {}", status),
+            Err(err) => log::error!("failed to stop guest instance cleanly: {:?}", err),
+        },
+        (name, _) = signals.recv() => log::warn!("received {} again, exiting immediately", name),
+    }
+
+    log::logger().flush();
+    Ok(ExitCode::from(128 + number as u8))
+}